@@ -1723,382 +1723,6917 @@ fn coool() {
 }
 
 mod stateful {
+    use serde::{Deserialize, Serialize};
     use std::collections::HashSet;
+    pub use tree::{Control, TreeLike, Visitor};
+    use tree_derive::TreeLike;
 
-    #[derive(Debug, Clone)]
+    // Drop below and the walkers are iterative, but the derived Clone, Debug,
+    // PartialEq and serde impls recurse once per level: keep them to trees of
+    // reasonable depth, and use merkle::Digests::same or a walk on deep ones
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, TreeLike)]
     pub struct Node {
         pub name: String,
+        #[tree(children)]
         pub children: Vec<Node>,
+        // bytes on disk, for files read by Node::from_dir
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub size: Option<u64>,
     }
 
-    pub trait Visitor<N> {
-        fn process(&mut self, node: &N);
+    // the traversal machinery shared by every tree type in here: anything
+    // TreeLike can be walked by any Visitor of it
+    pub mod tree {
+        use futures::stream::{FuturesUnordered, StreamExt};
+        use std::{
+            collections::VecDeque,
+            convert::Infallible,
+            fmt,
+            num::NonZeroUsize,
+            ops::{Bound, RangeBounds},
+        };
+
+        // what a visitor wants the walk to do after seeing a node
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum Control {
+            Continue,
+            // don't descend into this node's children, carry on with its siblings
+            SkipChildren,
+            // end the walk now
+            Stop,
+        }
+
+        pub trait Visitor<N> {
+            fn process(&mut self, node: &N) -> Control;
+
+            // what the walkers actually call, with the node's depth (the root
+            // is 0). only visitors that care about depth override it
+            fn process_at(&mut self, node: &N, depth: usize) -> Control {
+                self.process(node)
+            }
+
+            // enter/leave bracket a node's subtree in `walk_enter_leave`. no-ops by
+            // default so plain visitors only need `process`
+            fn enter(&mut self, node: &N) -> Control {
+                Control::Continue
+            }
+
+            fn leave(&mut self, node: &N) {}
+
+            // enter/leave with the depth, as process_at is to process
+            fn enter_at(&mut self, node: &N, depth: usize) -> Control {
+                self.enter(node)
+            }
+
+            fn leave_at(&mut self, node: &N, depth: usize) {
+                self.leave(node)
+            }
+
+            // adaptors in the style of Iterator's, each a plain generic
+            // wrapper. the wrapped visitors come back out with into_inner
+
+            // runs both, see And for how their answers mix
+            fn and<B: Visitor<N>>(self, other: B) -> And<Self, B>
+            where
+                Self: Sized,
+            {
+                And {
+                    a: Side::new(self),
+                    b: Side::new(other),
+                }
+            }
+
+            // only nodes matching `pred` are passed on. the others' children
+            // are still walked
+            fn filter<P: FnMut(&N) -> bool>(self, pred: P) -> Filter<Self, P>
+            where
+                Self: Sized,
+            {
+                Filter {
+                    inner: self,
+                    pred,
+                    entered: vec![],
+                }
+            }
+
+            fn leaves_only(self) -> LeavesOnly<Self>
+            where
+                Self: Sized,
+            {
+                LeavesOnly(self)
+            }
+
+            // only nodes at these depths are passed on, and nothing below the
+            // range is walked
+            fn depth_range<R: RangeBounds<usize>>(self, range: R) -> DepthRange<Self, R>
+            where
+                Self: Sized,
+            {
+                DepthRange { inner: self, range }
+            }
+        }
+
+        // any closure over a node is a visitor that sees every node, so the hof
+        // style and the struct style go through the same walkers. this is also
+        // why there's no blanket impl for Box<V> or &mut V: those can be closures
+        // too, and the two impls would overlap
+        impl<N, F> Visitor<N> for F
+        where
+            F: FnMut(&N),
+        {
+            fn process(&mut self, node: &N) -> Control {
+                self(node);
+                Control::Continue
+            }
+        }
+
+        pub trait TreeLike: Sized {
+            fn children(&self) -> &[Self];
+        }
+
+        // where one side of an And is. as in the registry, skipping lasts
+        // until the walk comes back up to the depth it started at, which
+        // takes a depth-first walk: walk_pre, walk_enter_leave or a registry
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        enum Status {
+            Active,
+            Skipping(usize),
+            Stopped,
+        }
+
+        struct Side<V> {
+            visitor: V,
+            status: Status,
+        }
+
+        impl<V> Side<V> {
+            fn new(visitor: V) -> Self {
+                Side {
+                    visitor,
+                    status: Status::Active,
+                }
+            }
+
+            // runs `f` unless this side is stopped or below a node it skipped
+            fn call(&mut self, depth: usize, f: impl FnOnce(&mut V) -> Control) {
+                if let Status::Skipping(d) = self.status
+                    && depth <= d
+                {
+                    self.status = Status::Active;
+                }
+                if self.status != Status::Active {
+                    return;
+                }
+                match f(&mut self.visitor) {
+                    Control::Continue => {}
+                    Control::SkipChildren => self.status = Status::Skipping(depth),
+                    Control::Stop => self.status = Status::Stopped,
+                }
+            }
+
+            // a node is left by every side that entered it
+            fn left(&self, depth: usize) -> bool {
+                match self.status {
+                    Status::Active => true,
+                    Status::Skipping(d) => depth <= d,
+                    Status::Stopped => false,
+                }
+            }
+        }
+
+        // runs both visitors. each one's SkipChildren and Stop only apply to
+        // itself: the walk goes into a subtree if either side wants it, and
+        // ends once both have stopped
+        pub struct And<A, B> {
+            a: Side<A>,
+            b: Side<B>,
+        }
+
+        impl<A, B> And<A, B> {
+            pub fn into_inner(self) -> (A, B) {
+                (self.a.visitor, self.b.visitor)
+            }
+
+            fn control(&self) -> Control {
+                match (self.a.status, self.b.status) {
+                    (Status::Active, _) | (_, Status::Active) => Control::Continue,
+                    (Status::Stopped, Status::Stopped) => Control::Stop,
+                    _ => Control::SkipChildren,
+                }
+            }
+        }
+
+        impl<N, A: Visitor<N>, B: Visitor<N>> Visitor<N> for And<A, B> {
+            // without a depth there is no telling where a skip ends, so
+            // both sides see every node until they stop
+            fn process(&mut self, node: &N) -> Control {
+                for status in [&mut self.a.status, &mut self.b.status] {
+                    if *status != Status::Stopped {
+                        *status = Status::Active;
+                    }
+                }
+                self.a.call(0, |v| v.process(node));
+                self.b.call(0, |v| v.process(node));
+                self.control()
+            }
+
+            fn process_at(&mut self, node: &N, depth: usize) -> Control {
+                self.a.call(depth, |v| v.process_at(node, depth));
+                self.b.call(depth, |v| v.process_at(node, depth));
+                self.control()
+            }
+
+            // as process, the sides only drop out once they stop
+            fn enter(&mut self, node: &N) -> Control {
+                for status in [&mut self.a.status, &mut self.b.status] {
+                    if *status != Status::Stopped {
+                        *status = Status::Active;
+                    }
+                }
+                self.a.call(0, |v| v.enter(node));
+                self.b.call(0, |v| v.enter(node));
+                self.control()
+            }
+
+            fn leave(&mut self, node: &N) {
+                if self.a.status != Status::Stopped {
+                    self.a.visitor.leave(node);
+                }
+                if self.b.status != Status::Stopped {
+                    self.b.visitor.leave(node);
+                }
+            }
+
+            fn enter_at(&mut self, node: &N, depth: usize) -> Control {
+                self.a.call(depth, |v| v.enter_at(node, depth));
+                self.b.call(depth, |v| v.enter_at(node, depth));
+                self.control()
+            }
+
+            fn leave_at(&mut self, node: &N, depth: usize) {
+                if self.a.left(depth) {
+                    self.a.visitor.leave_at(node, depth);
+                }
+                if self.b.left(depth) {
+                    self.b.visitor.leave_at(node, depth);
+                }
+            }
+        }
+
+        // `entered` has one entry per node entered and not yet left: whether
+        // it was passed on, so its leave goes the same way even if `pred`
+        // would answer differently the second time
+        pub struct Filter<V, P> {
+            inner: V,
+            pred: P,
+            entered: Vec<bool>,
+        }
+
+        impl<V, P> Filter<V, P> {
+            pub fn into_inner(self) -> V {
+                self.inner
+            }
+        }
+
+        impl<N, V: Visitor<N>, P: FnMut(&N) -> bool> Visitor<N> for Filter<V, P> {
+            fn process(&mut self, node: &N) -> Control {
+                if (self.pred)(node) {
+                    self.inner.process(node)
+                } else {
+                    Control::Continue
+                }
+            }
+
+            fn process_at(&mut self, node: &N, depth: usize) -> Control {
+                if (self.pred)(node) {
+                    self.inner.process_at(node, depth)
+                } else {
+                    Control::Continue
+                }
+            }
+
+            fn enter(&mut self, node: &N) -> Control {
+                let pass = (self.pred)(node);
+                self.entered.push(pass);
+                if pass {
+                    self.inner.enter(node)
+                } else {
+                    Control::Continue
+                }
+            }
+
+            fn enter_at(&mut self, node: &N, depth: usize) -> Control {
+                let pass = (self.pred)(node);
+                self.entered.push(pass);
+                if pass {
+                    self.inner.enter_at(node, depth)
+                } else {
+                    Control::Continue
+                }
+            }
+
+            fn leave(&mut self, node: &N) {
+                if self.entered.pop() == Some(true) {
+                    self.inner.leave(node)
+                }
+            }
+
+            fn leave_at(&mut self, node: &N, depth: usize) {
+                if self.entered.pop() == Some(true) {
+                    self.inner.leave_at(node, depth)
+                }
+            }
+        }
+
+        pub struct LeavesOnly<V>(V);
+
+        impl<V> LeavesOnly<V> {
+            pub fn into_inner(self) -> V {
+                self.0
+            }
+        }
+
+        impl<N: TreeLike, V: Visitor<N>> Visitor<N> for LeavesOnly<V> {
+            fn process(&mut self, node: &N) -> Control {
+                if node.children().is_empty() {
+                    self.0.process(node)
+                } else {
+                    Control::Continue
+                }
+            }
+
+            fn process_at(&mut self, node: &N, depth: usize) -> Control {
+                if node.children().is_empty() {
+                    self.0.process_at(node, depth)
+                } else {
+                    Control::Continue
+                }
+            }
+
+            fn enter(&mut self, node: &N) -> Control {
+                if node.children().is_empty() {
+                    self.0.enter(node)
+                } else {
+                    Control::Continue
+                }
+            }
+
+            fn enter_at(&mut self, node: &N, depth: usize) -> Control {
+                if node.children().is_empty() {
+                    self.0.enter_at(node, depth)
+                } else {
+                    Control::Continue
+                }
+            }
+
+            fn leave(&mut self, node: &N) {
+                if node.children().is_empty() {
+                    self.0.leave(node)
+                }
+            }
+
+            fn leave_at(&mut self, node: &N, depth: usize) {
+                if node.children().is_empty() {
+                    self.0.leave_at(node, depth)
+                }
+            }
+        }
+
+        // goes by the depth the walker passes in. called without one, as a
+        // plain process/enter/leave, it has nothing to go by and takes every
+        // node to be in range
+        pub struct DepthRange<V, R> {
+            inner: V,
+            range: R,
+        }
+
+        impl<V, R: RangeBounds<usize>> DepthRange<V, R> {
+            pub fn into_inner(self) -> V {
+                self.inner
+            }
+
+            // nothing at `depth` or below it is in range
+            fn past(&self, depth: usize) -> bool {
+                match self.range.end_bound() {
+                    Bound::Included(end) => depth > *end,
+                    Bound::Excluded(end) => depth >= *end,
+                    Bound::Unbounded => false,
+                }
+            }
+
+            fn control(&mut self, depth: usize, run: impl FnOnce(&mut V) -> Control) -> Control {
+                if self.past(depth) {
+                    return Control::SkipChildren;
+                }
+                if !self.range.contains(&depth) {
+                    return Control::Continue;
+                }
+                match run(&mut self.inner) {
+                    Control::Continue if self.past(depth + 1) => Control::SkipChildren,
+                    control => control,
+                }
+            }
+        }
+
+        impl<N, V: Visitor<N>, R: RangeBounds<usize>> Visitor<N> for DepthRange<V, R> {
+            fn process(&mut self, node: &N) -> Control {
+                self.inner.process(node)
+            }
+
+            fn process_at(&mut self, node: &N, depth: usize) -> Control {
+                self.control(depth, |v| v.process_at(node, depth))
+            }
+
+            fn enter(&mut self, node: &N) -> Control {
+                self.inner.enter(node)
+            }
+
+            fn enter_at(&mut self, node: &N, depth: usize) -> Control {
+                self.control(depth, |v| v.enter_at(node, depth))
+            }
+
+            fn leave(&mut self, node: &N) {
+                self.inner.leave(node)
+            }
+
+            fn leave_at(&mut self, node: &N, depth: usize) {
+                if self.range.contains(&depth) {
+                    self.inner.leave_at(node, depth)
+                }
+            }
+        }
+
+        // lazy counterparts of the walkers, for when std/itertools adaptors are a
+        // better fit than a Visitor. the root is at depth 0
+        pub fn pre<N: TreeLike>(root: &N) -> impl Iterator<Item = (usize, &N)> {
+            Pre {
+                stack: vec![(0, root)],
+            }
+        }
+
+        pub fn post<N: TreeLike>(root: &N) -> impl Iterator<Item = &N> {
+            Post {
+                stack: vec![(root, false)],
+            }
+        }
+
+        pub fn bfs<N: TreeLike>(root: &N) -> impl Iterator<Item = (usize, &N)> {
+            Bfs {
+                queue: VecDeque::from(vec![(0, root)]),
+            }
+        }
+
+        // the iterators hold the same explicit stacks/queue as the walkers, they
+        // just hand back one node per call to next()
+        struct Pre<'a, N> {
+            stack: Vec<(usize, &'a N)>,
+        }
+
+        impl<'a, N: TreeLike> Iterator for Pre<'a, N> {
+            type Item = (usize, &'a N);
+
+            fn next(&mut self) -> Option<Self::Item> {
+                let (depth, node) = self.stack.pop()?;
+                self.stack
+                    .extend(node.children().iter().rev().map(|c| (depth + 1, c)));
+                Some((depth, node))
+            }
+        }
+
+        struct Post<'a, N> {
+            stack: Vec<(&'a N, bool)>,
+        }
+
+        impl<'a, N: TreeLike> Iterator for Post<'a, N> {
+            type Item = &'a N;
+
+            fn next(&mut self) -> Option<Self::Item> {
+                loop {
+                    let (node, expanded) = self.stack.pop()?;
+                    if expanded {
+                        return Some(node);
+                    }
+                    self.stack.push((node, true));
+                    self.stack
+                        .extend(node.children().iter().rev().map(|c| (c, false)));
+                }
+            }
+        }
+
+        struct Bfs<'a, N> {
+            queue: VecDeque<(usize, &'a N)>,
+        }
+
+        impl<'a, N: TreeLike> Iterator for Bfs<'a, N> {
+            type Item = (usize, &'a N);
+
+            fn next(&mut self) -> Option<Self::Item> {
+                let (depth, node) = self.queue.pop_front()?;
+                self.queue
+                    .extend(node.children().iter().map(|c| (depth + 1, c)));
+                Some((depth, node))
+            }
+        }
+
+        // every walker returns `Control::Stop` if a visitor ended the walk early.
+        // they keep their own stack instead of recursing, so a tree can be as deep
+        // as the heap allows rather than as deep as the thread's stack allows
+        pub fn walk_pre<N, V>(node: &N, visitor: &mut V) -> Control
+        where
+            N: TreeLike,
+            V: Visitor<N> + ?Sized,
+        {
+            let mut stack = vec![(node, 0)];
+
+            while let Some((node, depth)) = stack.pop() {
+                match visitor.process_at(node, depth) {
+                    // reversed so the first child is popped first
+                    Control::Continue => {
+                        stack.extend(node.children().iter().rev().map(|c| (c, depth + 1)))
+                    }
+                    Control::SkipChildren => {}
+                    Control::Stop => return Control::Stop,
+                }
+            }
+
+            Control::Continue
+        }
+
+        // children before their parent: what bottom-up rollups need. the children
+        // have already been seen by the time `process` runs, so `SkipChildren` is
+        // the same as `Continue` here
+        pub fn walk_post<N, V>(node: &N, visitor: &mut V) -> Control
+        where
+            N: TreeLike,
+            V: Visitor<N> + ?Sized,
+        {
+            // a node goes on the stack twice: once to push its children, and once
+            // more, underneath them, to be processed after they are done
+            let mut stack = vec![(node, 0, false)];
+
+            while let Some((node, depth, expanded)) = stack.pop() {
+                if expanded {
+                    if visitor.process_at(node, depth) == Control::Stop {
+                        return Control::Stop;
+                    }
+                } else {
+                    stack.push((node, depth, true));
+                    let children = node.children().iter().rev();
+                    stack.extend(children.map(|c| (c, depth + 1, false)));
+                }
+            }
+
+            Control::Continue
+        }
+
+        // breadth-first: every node at depth d before any node at depth d + 1
+        pub fn walk_level_order<N, V>(node: &N, visitor: &mut V) -> Control
+        where
+            N: TreeLike,
+            V: Visitor<N> + ?Sized,
+        {
+            let mut queue = VecDeque::new();
+            queue.push_back((node, 0));
+
+            while let Some((node, depth)) = queue.pop_front() {
+                match visitor.process_at(node, depth) {
+                    Control::Continue => {
+                        queue.extend(node.children().iter().map(|c| (c, depth + 1)))
+                    }
+                    Control::SkipChildren => {}
+                    Control::Stop => return Control::Stop,
+                }
+            }
+
+            Control::Continue
+        }
+
+        // enter on the way down, leave on the way back up. a skipped node is still
+        // left; a stopped walk leaves nothing that is still open
+        pub fn walk_enter_leave<N, V>(node: &N, visitor: &mut V) -> Control
+        where
+            N: TreeLike,
+            V: Visitor<N> + ?Sized,
+        {
+            // same two-visit trick as walk_post
+            let mut stack = vec![(node, 0, false)];
+
+            while let Some((node, depth, entered)) = stack.pop() {
+                if entered {
+                    visitor.leave_at(node, depth);
+                    continue;
+                }
+
+                match visitor.enter_at(node, depth) {
+                    Control::Continue => {
+                        stack.push((node, depth, true));
+                        let children = node.children().iter().rev();
+                        stack.extend(children.map(|c| (c, depth + 1, false)));
+                    }
+                    Control::SkipChildren => stack.push((node, depth, true)),
+                    Control::Stop => return Control::Stop,
+                }
+            }
+
+            Control::Continue
+        }
+
+        // where a node sits in the walk
+        pub struct Context<'a, N> {
+            // the root is at depth 0
+            pub depth: usize,
+            // which child of its parent it is, None for the root
+            pub index: Option<usize>,
+            // root first, parent last; empty for the root
+            pub ancestors: &'a [&'a N],
+        }
+
+        impl<N> Context<'_, N> {
+            pub fn parent(&self) -> Option<&N> {
+                self.ancestors.last().copied()
+            }
+
+            // the ancestors' names and then `node`'s, joined with `/`
+            pub fn path<'n>(&'n self, node: &'n N, name: impl Fn(&'n N) -> &'n str) -> String {
+                let names: Vec<&str> = self
+                    .ancestors
+                    .iter()
+                    .chain([&node])
+                    .map(|n| name(n))
+                    .collect();
+                names.join("/")
+            }
+        }
+
+        // a Visitor that is told where each node sits, instead of having to
+        // keep a shadow stack of its own
+        pub trait ContextVisitor<N> {
+            fn process(&mut self, node: &N, cx: &Context<N>) -> Control;
+        }
+
+        impl<N, F> ContextVisitor<N> for F
+        where
+            F: FnMut(&N, &Context<N>),
+        {
+            fn process(&mut self, node: &N, cx: &Context<N>) -> Control {
+                self(node, cx);
+                Control::Continue
+            }
+        }
+
+        // pre-order, like walk_pre. the ancestors are one vec kept in step
+        // with the walk: popping a node at depth d cuts it back to d entries
+        pub fn walk_with_context<N, V>(node: &N, visitor: &mut V) -> Control
+        where
+            N: TreeLike,
+            V: ContextVisitor<N> + ?Sized,
+        {
+            let mut ancestors: Vec<&N> = vec![];
+            let mut stack = vec![(node, 0, None)];
+
+            while let Some((node, depth, index)) = stack.pop() {
+                ancestors.truncate(depth);
+                let cx = Context {
+                    depth,
+                    index,
+                    ancestors: &ancestors,
+                };
+
+                match visitor.process(node, &cx) {
+                    Control::Continue => {
+                        ancestors.push(node);
+                        let children = node.children().iter().enumerate().rev();
+                        stack.extend(children.map(|(i, c)| (c, depth + 1, Some(i))));
+                    }
+                    Control::SkipChildren => {}
+                    Control::Stop => return Control::Stop,
+                }
+            }
+
+            Control::Continue
+        }
+
+        // a Visitor that can fail, e.g. one checking the tree is well formed
+        pub trait TryVisitor<N> {
+            type Error;
+
+            fn try_process(&mut self, node: &N) -> Result<Control, Self::Error>;
+        }
+
+        impl<N, E, F> TryVisitor<N> for F
+        where
+            F: FnMut(&N) -> Result<Control, E>,
+        {
+            type Error = E;
+
+            fn try_process(&mut self, node: &N) -> Result<Control, E> {
+                self(node)
+            }
+        }
+
+        // what a TryVisitor returned and where: child indices from the root,
+        // the same paths diff uses
+        #[derive(Debug, Clone, PartialEq)]
+        pub struct WalkError<E> {
+            pub path: Vec<usize>,
+            pub error: E,
+        }
+
+        impl<E: fmt::Display> fmt::Display for WalkError<E> {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "at {:?}: {}", self.path, self.error)
+            }
+        }
+
+        impl<E: fmt::Debug + fmt::Display> std::error::Error for WalkError<E> {}
+
+        // pre-order, giving up at the first error
+        pub fn try_walk<N, V>(node: &N, visitor: &mut V) -> Result<Control, WalkError<V::Error>>
+        where
+            N: TreeLike,
+            V: TryVisitor<N> + ?Sized,
+        {
+            try_walk_with(node, visitor, &mut |e| Err(e))
+        }
+
+        // pre-order, carrying on past errors and returning all of them. the
+        // children of a node that failed are skipped
+        pub fn try_walk_all<N, V>(
+            node: &N,
+            visitor: &mut V,
+        ) -> Result<Control, Vec<WalkError<V::Error>>>
+        where
+            N: TreeLike,
+            V: TryVisitor<N> + ?Sized,
+        {
+            let mut errors = vec![];
+            let control = try_walk_with(node, visitor, &mut |e| -> Result<(), Infallible> {
+                errors.push(e);
+                Ok(())
+            });
+            let Ok(control) = control;
+            if errors.is_empty() {
+                Ok(control)
+            } else {
+                Err(errors)
+            }
+        }
+
+        // `on_error` decides whether the walk goes on
+        fn try_walk_with<N, V, R>(
+            node: &N,
+            visitor: &mut V,
+            on_error: &mut impl FnMut(WalkError<V::Error>) -> Result<(), R>,
+        ) -> Result<Control, R>
+        where
+            N: TreeLike,
+            V: TryVisitor<N> + ?Sized,
+        {
+            let mut path: Vec<usize> = vec![];
+            let mut stack = vec![(node, 0_usize, None)];
+
+            while let Some((node, depth, index)) = stack.pop() {
+                path.truncate(depth.saturating_sub(1));
+                path.extend(index);
+
+                match visitor.try_process(node) {
+                    Ok(Control::Continue) => {
+                        let children = node.children().iter().enumerate().rev();
+                        stack.extend(children.map(|(i, c)| (c, depth + 1, Some(i))));
+                    }
+                    Ok(Control::SkipChildren) => {}
+                    Ok(Control::Stop) => return Ok(Control::Stop),
+                    Err(error) => on_error(WalkError {
+                        path: path.clone(),
+                        error,
+                    })?,
+                }
+            }
+
+            Ok(Control::Continue)
+        }
+
+        // a Visitor whose work per node is I/O: a stat, a request to some
+        // service. it takes &self since several nodes are in flight at once,
+        // so any state it keeps needs a Mutex or an atomic
+        pub trait AsyncVisitor<N> {
+            fn process(&self, node: &N) -> impl Future<Output = Control>;
+        }
+
+        impl<N, F> AsyncVisitor<N> for F
+        where
+            F: AsyncFn(&N) -> Control,
+        {
+            fn process(&self, node: &N) -> impl Future<Output = Control> {
+                self(node)
+            }
+        }
+
+        // processes up to `limit` nodes at once, so sibling subtrees are
+        // walked side by side. a node is always done before any of its
+        // children start, but otherwise they finish in whatever order the
+        // I/O does. with a limit of 1 this is walk_pre. runs on any executor
+        pub async fn walk_async<N, V>(node: &N, visitor: &V, limit: NonZeroUsize) -> Control
+        where
+            N: TreeLike,
+            V: AsyncVisitor<N>,
+        {
+            // not started yet. a stack, so the walk goes deep before it goes
+            // wide and this stays about as small as walk_pre's
+            let mut waiting = vec![node];
+            let mut running = FuturesUnordered::new();
+
+            loop {
+                while running.len() < limit.get()
+                    && let Some(node) = waiting.pop()
+                {
+                    running.push(async move { (node, visitor.process(node).await) });
+                }
+
+                match running.next().await {
+                    None => return Control::Continue,
+                    Some((node, Control::Continue)) => waiting.extend(node.children().iter().rev()),
+                    Some((_, Control::SkipChildren)) => {}
+                    // whatever is still running is dropped, i.e. cancelled
+                    Some((_, Control::Stop)) => return Control::Stop,
+                }
+            }
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+            use crate::stateful::{
+                nodetype::CoolNode,
+                tests::{leaf, Prune},
+                typestate,
+                visitor::Counter,
+                Node,
+            };
+
+            fn cool(name: &str, children: Vec<CoolNode>) -> CoolNode {
+                CoolNode {
+                    name: name.into(),
+                    nick: name.to_uppercase(),
+                    children,
+                }
+            }
+
+            // the same closure-based visitor over each tree type
+            fn names<N: TreeLike>(root: &N, name: impl Fn(&N) -> String) -> Vec<String> {
+                let mut names = vec![];
+                walk_pre(root, &mut |n: &N| names.push(name(n)));
+                names
+            }
+
+            #[test]
+            fn every_tree_type() {
+                let node = Node::new("a", vec![Node::new("b", vec![]), Node::new("c", vec![])]);
+                assert_eq!(names(&node, |n| n.name.clone()), ["a", "b", "c"]);
+
+                let cool = cool("a", vec![cool("b", vec![cool("c", vec![])])]);
+                assert_eq!(names(&cool, |n| n.nick.clone()), ["A", "B", "C"]);
+
+                let graph =
+                    typestate::GraphNode::new("a", vec![typestate::GraphNode::new("a", vec![])]);
+                assert_eq!(names(&graph, |n| n.name.clone()), ["a", "a"]);
+
+                let leaves = post(&cool).filter(|n| n.children().is_empty()).count();
+                assert_eq!(leaves, 1);
+            }
+
+            // a list: at most one child each
+            #[derive(tree_derive::TreeLike)]
+            struct Link(u32, #[tree(children)] Option<Box<Link>>);
+
+            #[derive(tree_derive::TreeLike)]
+            struct Frozen {
+                name: &'static str,
+                #[tree(children)]
+                children: Box<[Self]>,
+            }
+
+            #[test]
+            fn derived_containers() {
+                let list = Link(1, Some(Box::new(Link(2, Some(Box::new(Link(3, None)))))));
+                let values: Vec<u32> = pre(&list).map(|(_, l)| l.0).collect();
+                assert_eq!(values, [1, 2, 3]);
+
+                let frozen = Frozen {
+                    name: "a",
+                    children: vec![
+                        Frozen {
+                            name: "b",
+                            children: Box::new([]),
+                        },
+                        Frozen {
+                            name: "c",
+                            children: Box::new([]),
+                        },
+                    ]
+                    .into(),
+                };
+                let names: Vec<&str> = bfs(&frozen).map(|(_, f)| f.name).collect();
+                assert_eq!(names, ["a", "b", "c"]);
+            }
+
+            #[test]
+            fn closures_and_structs_mix() {
+                let node = Node::new("a", vec![Node::new("b", vec![Node::new("c", vec![])])]);
+
+                let mut seen = 0;
+                let mut closure = |_: &Node| seen += 1;
+                let mut counter = Counter::default();
+                walk_level_order(&node, &mut closure);
+                walk_level_order(&node, &mut counter);
+                assert_eq!((seen, counter.total, counter.leaves), (3, 3, 1));
+
+                // both kinds behind one trait object
+                let mut depth = 0;
+                let mut visitors: Vec<Box<dyn Visitor<Node> + '_>> = vec![
+                    Box::new(Counter::default()),
+                    Box::new(|n: &Node| depth = depth.max(n.name.len())),
+                ];
+                for v in &mut visitors {
+                    assert_eq!(walk_post(&node, &mut **v), Control::Continue);
+                }
+                drop(visitors);
+                assert_eq!(depth, 1);
+            }
+
+            #[test]
+            fn combinators() {
+                let node = abcxde();
+
+                let mut leaves = 0;
+                walk_pre(&node, &mut (|_: &Node| leaves += 1).leaves_only());
+                assert_eq!(leaves, 3);
+
+                let mut counter = Counter::default().leaves_only().and(Counter::default());
+                walk_pre(&node, &mut counter);
+                let (leaves, all) = counter.into_inner();
+                assert_eq!((leaves.into_inner().total, all.total), (3, 6));
+
+                let mut names = vec![];
+                let short = |n: &Node| n.name != "c";
+                walk_pre(
+                    &node,
+                    &mut (|n: &Node| names.push(n.name.clone())).filter(short),
+                );
+                assert_eq!(names, ["a", "b", "x", "d", "e"]);
+
+                // every walker passes the depth along
+                let middle = |walk: fn(&Node, &mut dyn Visitor<Node>) -> Control| {
+                    let mut names = vec![];
+                    let mut seen = |n: &Node| names.push(n.name.clone());
+                    walk(&node, &mut (&mut seen).depth_range(1..=2));
+                    names
+                };
+                assert_eq!(middle(|n, v| walk_pre(n, v)), ["b", "c", "d", "e"]);
+                assert_eq!(middle(|n, v| walk_post(n, v)), ["c", "d", "b", "e"]);
+                assert_eq!(middle(|n, v| walk_level_order(n, v)), ["b", "e", "c", "d"]);
+
+                let mut seen = vec![];
+                let mut visited = 0;
+                let mut all = |n: &Node| seen.push(n.name.clone());
+                let mut both = (&mut all).and((|_: &Node| visited += 1).depth_range(..2));
+                walk_pre(&node, &mut both);
+                assert_eq!((seen.len(), visited), (6, 3));
+            }
+
+            // a
+            // ├── b
+            // │   ├── c
+            // │   │   └── x
+            // │   └── d
+            // └── e
+            fn abcxde() -> Node {
+                Node::new(
+                    "a",
+                    vec![
+                        Node::new("b", vec![Node::new("c", vec![leaf("x")]), leaf("d")]),
+                        leaf("e"),
+                    ],
+                )
+            }
+
+            #[test]
+            fn and_skips_per_side() {
+                let node = abcxde();
+
+                let mut all = vec![];
+                let mut both = Prune::default().and(|n: &Node| all.push(n.name.clone()));
+                walk_pre(&node, &mut both);
+                let (skip, _) = both.into_inner();
+                assert_eq!(skip.0, ["a", "b", "e"]);
+                assert_eq!(all, ["a", "b", "c", "x", "d", "e"]);
+
+                let mut both = Prune::default().and(Counter::default());
+                walk_enter_leave(&node, &mut both);
+                let (skip, _) = both.into_inner();
+                assert_eq!(skip.0, ["a", "b", "/b", "e", "/e", "/a"]);
+            }
+
+            #[test]
+            fn depth_range_under_enter_leave() {
+                // c is filtered out before it reaches the range, which used
+                // to throw off a depth the range counted for itself
+                let mut node = abcxde();
+                node.children[0].name = "q".into();
+                let mut range = Prune::default()
+                    .depth_range(1..=2)
+                    .filter(|n: &Node| n.name != "c");
+                walk_enter_leave(&node, &mut range);
+                let names = range.into_inner().into_inner().0;
+                assert_eq!(names, ["q", "d", "/d", "/q", "e", "/e"]);
+            }
+
+            #[test]
+            fn depth_range_without_a_depth() {
+                // out of range by depth, but a plain call has none to go by
+                let node = abcxde();
+                let mut range = Prune::default().depth_range(1..);
+                assert_eq!(range.process(&node), Control::Continue);
+
+                let mut both = Counter::default().and(range);
+                assert_eq!(both.process(&node), Control::Continue);
+                assert_eq!(both.enter(&node), Control::Continue);
+                both.leave(&node);
+                let (counter, range) = both.into_inner();
+                assert_eq!(counter.total, 1);
+                assert_eq!(range.into_inner().0, ["a", "a", "a", "/a"]);
+            }
+
+            #[test]
+            fn filter_pairs_enter_with_leave() {
+                // a predicate that changes its mind every call
+                let mut flip = false;
+                let mut alternate = Prune::default().filter(move |_: &Node| {
+                    flip = !flip;
+                    flip
+                });
+                walk_enter_leave(&abcxde(), &mut alternate);
+                // a, c and d pass, once each way
+                assert_eq!(alternate.into_inner().0, ["a", "c", "/c", "d", "/d", "/a"]);
+            }
+        }
+    }
+
+    struct State {
+        n: usize,
+        names: Vec<String>,
+    }
+
+    impl Visitor<Node> for State {
+        fn process(&mut self, node: &Node) -> Control {
+            self.names.push(node.name.clone());
+            Control::Continue
+        }
+    }
+
+    // the derived drop glue recurses once per level and overflows the stack on
+    // a deep enough chain. hand the grandchildren up to a flat worklist instead
+    impl Drop for Node {
+        fn drop(&mut self) {
+            let mut stack = std::mem::take(&mut self.children);
+
+            while let Some(mut node) = stack.pop() {
+                stack.append(&mut node.children);
+            }
+        }
+    }
+
+    impl Node {
+        pub fn new(name: &str, children: Vec<Node>) -> Self {
+            Node {
+                name: name.into(),
+                children,
+                size: None,
+            }
+        }
+
+        pub fn dfs(&self) {
+            let mut state = State {
+                n: 0,
+                names: vec![],
+            };
+            tree::walk_pre(self, &mut state);
+        }
+
+        // lazy counterparts of the walkers, for when std/itertools adaptors
+        // are a better fit than a Visitor
+        pub fn iter_pre(&self) -> impl Iterator<Item = &Node> {
+            tree::pre(self).map(|(_, node)| node)
+        }
+
+        pub fn iter_post(&self) -> impl Iterator<Item = &Node> {
+            tree::post(self)
+        }
+
+        pub fn iter_bfs(&self) -> impl Iterator<Item = &Node> {
+            tree::bfs(self).map(|(_, node)| node)
+        }
+
+        // the root is at depth 0
+        pub fn iter_pre_with_depth(&self) -> impl Iterator<Item = (usize, &Node)> {
+            tree::pre(self)
+        }
+
+        pub fn iter_bfs_with_depth(&self) -> impl Iterator<Item = (usize, &Node)> {
+            tree::bfs(self)
+        }
+    }
+
+    // subtree sizes: an aggregation that can't be written on pre-order alone
+    #[derive(Default, Debug)]
+    struct Sizes {
+        open: Vec<usize>,
+        sizes: Vec<(String, usize)>,
+    }
+
+    impl Visitor<Node> for Sizes {
+        fn process(&mut self, node: &Node) -> Control {
+            Control::Continue
+        }
+
+        fn enter(&mut self, node: &Node) -> Control {
+            self.open.push(1);
+            Control::Continue
+        }
+
+        fn leave(&mut self, node: &Node) {
+            let size = self.open.pop().unwrap_or(1);
+            if let Some(parent) = self.open.last_mut() {
+                *parent += size;
+            }
+            self.sizes.push((node.name.clone(), size));
+        }
+    }
+
+    // first node with a given name; stops the walk as soon as it is found
+    struct Find<'a> {
+        name: &'a str,
+        seen: usize,
+        found: bool,
+    }
+
+    impl Visitor<Node> for Find<'_> {
+        fn process(&mut self, node: &Node) -> Control {
+            self.seen += 1;
+            if node.name == self.name {
+                self.found = true;
+                Control::Stop
+            } else {
+                Control::Continue
+            }
+        }
+    }
+
+    // full path of every leaf, `a/b/c` style
+    #[derive(Default, Debug)]
+    struct LeafPaths {
+        paths: Vec<String>,
+    }
+
+    impl tree::ContextVisitor<Node> for LeafPaths {
+        fn process(&mut self, node: &Node, cx: &tree::Context<Node>) -> Control {
+            if node.children.is_empty() {
+                self.paths.push(cx.path(node, |n| &n.name));
+            }
+            Control::Continue
+        }
+    }
+
+    // nodes named the same as their parent, e.g. `src/src`
+    #[derive(Default, Debug)]
+    struct Stutters {
+        found: Vec<String>,
+    }
+
+    impl tree::ContextVisitor<Node> for Stutters {
+        fn process(&mut self, node: &Node, cx: &tree::Context<Node>) -> Control {
+            if cx.parent().is_some_and(|p| p.name == node.name) {
+                self.found.push(cx.path(node, |n| &n.name));
+            }
+            Control::Continue
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Invalid {
+        EmptyName,
+        // two children of the node share this name
+        DuplicateChild(String),
+    }
+
+    impl std::fmt::Display for Invalid {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            match self {
+                Invalid::EmptyName => write!(f, "empty name"),
+                Invalid::DuplicateChild(name) => write!(f, "more than one child named {}", name),
+            }
+        }
+    }
+
+    // rejects what a path can't point at unambiguously
+    struct Validate;
+
+    impl tree::TryVisitor<Node> for Validate {
+        type Error = Invalid;
+
+        fn try_process(&mut self, node: &Node) -> Result<Control, Invalid> {
+            if node.name.is_empty() {
+                return Err(Invalid::EmptyName);
+            }
+            let mut seen = HashSet::new();
+            for child in &node.children {
+                if !seen.insert(&child.name) {
+                    return Err(Invalid::DuplicateChild(child.name.clone()));
+                }
+            }
+            Ok(Control::Continue)
+        }
+    }
+
+    mod closure {
+        pub fn run() {
+            let mut state = vec![];
+
+            // must be `mut` because closure mutates captured state (FnMut)
+            // FnMut is a type which mutates closure state
+            let mut g = |n| {
+                for x in 1..n {
+                    state.push(x);
+                }
+            };
+
+            g(5);
+        }
+    }
+
+    mod var {
+        use super::*;
+
+        pub fn dfs(node: &Node, names: &mut HashSet<String>) {
+            let mut stack = vec![node];
+
+            while let Some(node) = stack.pop() {
+                names.insert(node.name.clone());
+                stack.extend(node.children.iter().rev());
+            }
+        }
+    }
+
+    mod hof {
+        use super::*;
+
+        // any FnMut(&Node) is a Visitor<Node>, so this is just walk_pre
+        pub fn dfs<F>(node: &Node, f: &mut F)
+        where
+            F: FnMut(&Node),
+        {
+            tree::walk_pre(node, f);
+        }
+
+        pub fn uniq_names(root: &Node) {
+            let mut names = HashSet::<String>::new();
+
+            let mut process = |node: &Node| {
+                names.insert(node.name.clone());
+            };
+
+            dfs(root, &mut process);
+        }
+    }
+
+    mod typed {
+        use super::*;
+
+        #[derive(Default, Debug)]
+        pub struct State {
+            pub names: HashSet<String>,
+            pub total: usize,
+        }
+
+        pub fn dfs(node: &Node, state: &mut State) {
+            let mut stack = vec![node];
+
+            while let Some(node) = stack.pop() {
+                state.names.insert(node.name.clone());
+                state.total += 1;
+                stack.extend(node.children.iter().rev());
+            }
+        }
+    }
+
+    mod visitor {
+        use super::*;
+
+        // two independent types
+        #[derive(Default, Debug)]
+        pub struct Index {
+            pub names: HashSet<String>,
+            // names seen more than once
+            pub duplicates: HashSet<String>,
+        }
+
+        #[derive(Default, Debug)]
+        pub struct Counter {
+            pub total: usize,
+            pub leaves: usize,
+            // sum of the sizes, i.e. disk usage for a Node::from_dir tree
+            pub bytes: u64,
+        }
+
+        // two independent types need different impls of dfs
+        fn dfs_names(node: &Node, state: &mut Index) {
+            let mut stack = vec![node];
+
+            while let Some(node) = stack.pop() {
+                state.names.insert(node.name.clone());
+                stack.extend(node.children.iter().rev());
+            }
+        }
+
+        fn dfs_count(node: &Node, state: &mut Counter) {
+            let mut stack = vec![node];
+
+            while let Some(node) = stack.pop() {
+                if node.children.is_empty() {
+                    state.leaves += 1;
+                }
+                state.total += 1;
+                stack.extend(node.children.iter().rev());
+            }
+        }
+
+        // two independent types need different impls of dfs
+        // make it generic over _visitor_ type: that's tree::walk_pre, with
+        // both types as a Visitor<Node>
+        impl Visitor<Node> for Index {
+            fn process(&mut self, node: &Node) -> Control {
+                if !self.names.insert(node.name.clone()) {
+                    self.duplicates.insert(node.name.clone());
+                }
+                Control::Continue
+            }
+        }
+
+        impl Visitor<Node> for Counter {
+            fn process(&mut self, node: &Node) -> Control {
+                if node.children.is_empty() {
+                    self.leaves += 1;
+                }
+                self.total += 1;
+                self.bytes += node.size.unwrap_or(0);
+                Control::Continue
+            }
+        }
+
+        // visitor state that can be split across threads and put back
+        // together. `split` hands out an empty visitor configured like self,
+        // `merge` folds in one that saw the nodes right after everything self
+        // saw, so order sensitive visitors come out the same as a serial walk
+        pub trait Merge: Sized {
+            fn split(&self) -> Self;
+            fn merge(&mut self, later: Self);
+        }
+
+        impl Merge for Index {
+            fn split(&self) -> Self {
+                Index::default()
+            }
+
+            fn merge(&mut self, later: Self) {
+                self.duplicates.extend(later.duplicates);
+                for name in later.names {
+                    if self.names.contains(&name) {
+                        self.duplicates.insert(name);
+                    } else {
+                        self.names.insert(name);
+                    }
+                }
+            }
+        }
+
+        impl Merge for Counter {
+            fn split(&self) -> Self {
+                Counter::default()
+            }
+
+            fn merge(&mut self, later: Self) {
+                self.total += later.total;
+                self.leaves += later.leaves;
+                self.bytes += later.bytes;
+            }
+        }
+    }
+
+    mod nodetype {
+        use super::TreeLike;
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Debug, Serialize, Deserialize, TreeLike)]
+        pub struct CoolNode {
+            pub name: String,
+            pub nick: String,
+            #[tree(children)]
+            pub children: Vec<CoolNode>,
+        }
+    }
+
+    mod typestate {
+        use super::TreeLike;
+        use serde::{Deserialize, Serialize};
+        use std::collections::{HashMap, HashSet, VecDeque};
+
+        // make node generic
+        // the markers are never serialized, so don't ask them to be (de)serializable
+        #[derive(Debug, Serialize, Deserialize, TreeLike)]
+        #[serde(bound = "")]
+        pub struct Node<T> {
+            pub name: String,
+            #[tree(children)]
+            pub children: Vec<Node<T>>,
+            #[serde(skip)]
+            _marker: std::marker::PhantomData<T>,
+        }
+
+        // iterative for the same reason as the untyped Node's
+        impl<T> Drop for Node<T> {
+            fn drop(&mut self) {
+                let mut stack = std::mem::take(&mut self.children);
+
+                while let Some(mut node) = stack.pop() {
+                    stack.append(&mut node.children);
+                }
+            }
+        }
+
+        impl<T> Node<T> {
+            pub fn new(name: &str, children: Vec<Node<T>>) -> Self {
+                Node {
+                    name: name.into(),
+                    children,
+                    _marker: std::marker::PhantomData,
+                }
+            }
+        }
+
+        #[derive(Debug)]
+        pub struct Graph;
+        #[derive(Debug)]
+        pub struct Tree;
+
+        pub type GraphNode = Node<Graph>;
+        pub type TreeNode = Node<Tree>;
+
+        // an owned Node can only spell out a tree, so in a GraphNode a name
+        // *is* the vertex: repeating a name under a second parent shares that
+        // vertex, repeating an ancestor's name is a back edge
+        impl GraphNode {
+            // only makes sense on Node in a Graph
+            pub fn has_cycle(&self) -> bool {
+                self.graph().has_cycle()
+            }
+
+            pub fn graph(&self) -> Digraph {
+                let mut graph = Digraph::new();
+                let root = graph.vertex(&self.name);
+                let mut stack = vec![(self, root)];
+
+                while let Some((node, id)) = stack.pop() {
+                    for child in &node.children {
+                        let child_id = graph.vertex(&child.name);
+                        graph.add_edge(id, child_id);
+                    }
+                    stack.extend(node.children.iter().rev().map(|c| (c, graph.ids[&c.name])));
+                }
+
+                graph
+            }
+        }
+
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        pub struct VertexId(usize);
+
+        impl VertexId {
+            // position in the order vertices were added
+            pub fn index(self) -> usize {
+                self.0
+            }
+        }
+
+        // adjacency-list graph in an arena: vertices are indices, so shared
+        // and back edges are just more ids in someone's edge list. there is
+        // at most one edge from a vertex to another, however often it's added
+        #[derive(Debug, Default)]
+        pub struct Digraph {
+            names: Vec<String>,
+            edges: Vec<Vec<VertexId>>,
+            ids: HashMap<String, VertexId>,
+            added: HashSet<(VertexId, VertexId)>,
+        }
+
+        impl Digraph {
+            pub fn new() -> Self {
+                Digraph::default()
+            }
+
+            // the vertex with this name, added if it isn't there yet
+            pub fn vertex(&mut self, name: &str) -> VertexId {
+                if let Some(id) = self.ids.get(name) {
+                    return *id;
+                }
+
+                let id = VertexId(self.names.len());
+                self.names.push(name.into());
+                self.edges.push(vec![]);
+                self.ids.insert(name.into(), id);
+                id
+            }
+
+            // a shared vertex written out with its children under each of its
+            // parents adds those edges again: only the first one counts
+            pub fn add_edge(&mut self, from: VertexId, to: VertexId) {
+                if self.added.insert((from, to)) {
+                    self.edges[from.0].push(to);
+                }
+            }
+
+            pub fn id(&self, name: &str) -> Option<VertexId> {
+                self.ids.get(name).copied()
+            }
+
+            pub fn name(&self, id: VertexId) -> &str {
+                &self.names[id.0]
+            }
+
+            pub fn successors(&self, id: VertexId) -> &[VertexId] {
+                &self.edges[id.0]
+            }
+
+            pub fn len(&self) -> usize {
+                self.names.len()
+            }
+
+            pub fn is_empty(&self) -> bool {
+                self.names.is_empty()
+            }
+
+            pub fn vertices(&self) -> impl Iterator<Item = VertexId> + use<> {
+                (0..self.names.len()).map(VertexId)
+            }
+
+            pub fn has_cycle(&self) -> bool {
+                self.cycle().is_some()
+            }
+
+            // some cycle, as the vertices along it in edge order, if there is one
+            pub fn cycle(&self) -> Option<Vec<VertexId>> {
+                #[derive(Clone, Copy, PartialEq)]
+                enum Colour {
+                    Unseen,
+                    OnPath,
+                    Done,
+                }
+
+                let mut colour = vec![Colour::Unseen; self.len()];
+
+                for start in self.vertices() {
+                    if colour[start.0] != Colour::Unseen {
+                        continue;
+                    }
+
+                    // the current dfs path, each vertex with the index of the
+                    // next edge to try
+                    colour[start.0] = Colour::OnPath;
+                    let mut path = vec![(start, 0)];
+
+                    while let Some((id, next)) = path.last_mut() {
+                        let Some(&to) = self.edges[id.0].get(*next) else {
+                            colour[id.0] = Colour::Done;
+                            path.pop();
+                            continue;
+                        };
+                        *next += 1;
+
+                        match colour[to.0] {
+                            Colour::Unseen => {
+                                colour[to.0] = Colour::OnPath;
+                                path.push((to, 0));
+                            }
+                            // an edge back into the current path closes a cycle
+                            Colour::OnPath => {
+                                let from = path.iter().position(|(v, _)| *v == to)?;
+                                return Some(path[from..].iter().map(|(v, _)| *v).collect());
+                            }
+                            Colour::Done => {}
+                        }
+                    }
+                }
+
+                None
+            }
+
+            // every vertex ahead of everything it has an edge to, or None if
+            // a cycle makes that impossible. vertices are taken in the order
+            // they become free of incoming edges
+            pub fn topo_sort(&self) -> Option<Vec<VertexId>> {
+                let mut incoming = vec![0; self.len()];
+                for to in self.edges.iter().flatten() {
+                    incoming[to.0] += 1;
+                }
+
+                let mut ready: VecDeque<VertexId> =
+                    self.vertices().filter(|v| incoming[v.0] == 0).collect();
+                let mut order = Vec::with_capacity(self.len());
+
+                while let Some(id) = ready.pop_front() {
+                    order.push(id);
+                    for to in &self.edges[id.0] {
+                        incoming[to.0] -= 1;
+                        if incoming[to.0] == 0 {
+                            ready.push_back(*to);
+                        }
+                    }
+                }
+
+                (order.len() == self.len()).then_some(order)
+            }
+
+            // everything reachable from `from`, itself included, breadth first
+            pub fn reachable(&self, from: VertexId) -> Vec<VertexId> {
+                let mut seen = vec![false; self.len()];
+                let mut queue = VecDeque::from(vec![from]);
+                let mut out = vec![];
+                seen[from.0] = true;
+
+                while let Some(id) = queue.pop_front() {
+                    out.push(id);
+                    for to in &self.edges[id.0] {
+                        if !seen[to.0] {
+                            seen[to.0] = true;
+                            queue.push_back(*to);
+                        }
+                    }
+                }
+
+                out
+            }
+
+            pub fn can_reach(&self, from: VertexId, to: VertexId) -> bool {
+                self.reachable(from).contains(&to)
+            }
+
+            // the root, if the graph is a tree: acyclic, exactly one vertex
+            // without a parent and none with more than one
+            pub fn tree_root(&self) -> Result<VertexId, NotATree> {
+                let names = |ids: &[VertexId]| -> Vec<String> {
+                    ids.iter().map(|id| self.name(*id).to_string()).collect()
+                };
+
+                if let Some(cycle) = self.cycle() {
+                    return Err(NotATree::Cycle(names(&cycle)));
+                }
+
+                let mut parents = vec![vec![]; self.len()];
+                for from in self.vertices() {
+                    for to in self.successors(from) {
+                        parents[to.0].push(from);
+                    }
+                }
+
+                let roots: Vec<VertexId> = self
+                    .vertices()
+                    .filter(|v| parents[v.0].is_empty())
+                    .collect();
+                if roots.len() != 1 {
+                    return Err(NotATree::Roots(names(&roots)));
+                }
+
+                if let Some(shared) = self.vertices().find(|v| parents[v.0].len() > 1) {
+                    return Err(NotATree::Shared {
+                        name: self.name(shared).to_string(),
+                        parents: names(&parents[shared.0]),
+                    });
+                }
+
+                Ok(roots[0])
+            }
+        }
+
+        impl TreeNode {
+            // only makes sense on Node in a Tree
+            pub fn balanced(&self) -> bool {
+                self.balanced_within(1)
+            }
+
+            // true if at every node, the tallest and shortest child subtrees
+            // differ in height by at most k
+            pub fn balanced_within(&self, k: usize) -> bool {
+                self.shape().sibling_spread <= k
+            }
+
+            pub fn height(&self) -> usize {
+                self.shape().height
+            }
+
+            pub fn depth_of(&self, name: &str) -> Option<usize> {
+                self.shape().depths.get(name).copied()
+            }
+
+            pub fn diameter(&self) -> usize {
+                self.shape().diameter
+            }
+
+            pub fn max_fanout(&self) -> usize {
+                self.shape().max_fanout
+            }
+
+            pub fn depth_histogram(&self) -> Vec<usize> {
+                self.shape().histogram
+            }
+
+            // every metric above from a single post-order pass
+            pub fn shape(&self) -> Shape {
+                let mut shape = Shape::default();
+                // heights of finished subtrees, in visiting order
+                let mut heights: Vec<usize> = vec![];
+                let mut stack = vec![(self, 0, false)];
+
+                while let Some((node, depth, expanded)) = stack.pop() {
+                    if !expanded {
+                        if shape.histogram.len() <= depth {
+                            shape.histogram.push(0);
+                        }
+                        shape.histogram[depth] += 1;
+                        shape.depths.entry(node.name.clone()).or_insert(depth);
+                        shape.max_fanout = shape.max_fanout.max(node.children.len());
+
+                        stack.push((node, depth, true));
+                        stack.extend(node.children.iter().rev().map(|c| (c, depth + 1, false)));
+                        continue;
+                    }
+
+                    let children = heights.split_off(heights.len() - node.children.len());
+                    let (tallest, second) = children.iter().fold((None, None), |(a, b), &h| {
+                        if Some(h) > a {
+                            (Some(h), a)
+                        } else {
+                            (a, b.max(Some(h)))
+                        }
+                    });
+
+                    if let (Some(max), Some(min)) = (tallest, children.iter().min()) {
+                        shape.sibling_spread = shape.sibling_spread.max(max - min);
+                    }
+
+                    // longest path through this node: down its two tallest children
+                    let through = match (tallest, second) {
+                        (Some(a), Some(b)) => a + b + 2,
+                        (Some(a), None) => a + 1,
+                        _ => 0,
+                    };
+                    shape.diameter = shape.diameter.max(through);
+
+                    heights.push(tallest.map_or(0, |h| h + 1));
+                }
+
+                shape.height = heights.pop().unwrap_or(0);
+                shape
+            }
+        }
+
+        // why a GraphNode can't become a TreeNode. names are the vertices, as
+        // in GraphNode::graph
+        #[derive(Debug, PartialEq)]
+        pub enum NotATree {
+            // the vertices around one cycle, in edge order
+            Cycle(Vec<String>),
+            // every vertex with no incoming edge; a tree has exactly one
+            Roots(Vec<String>),
+            // a vertex reached from more than one place, with each parent
+            // listed once per edge into it
+            Shared { name: String, parents: Vec<String> },
+        }
+
+        impl std::fmt::Display for NotATree {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                match self {
+                    NotATree::Cycle(names) => write!(f, "cycle through {}", names.join(" -> ")),
+                    NotATree::Roots(names) if names.is_empty() => write!(f, "no root"),
+                    NotATree::Roots(names) => write!(f, "several roots: {}", names.join(", ")),
+                    NotATree::Shared { name, parents } => {
+                        write!(f, "{} is shared by {}", name, parents.join(", "))
+                    }
+                }
+            }
+        }
+
+        impl std::error::Error for NotATree {}
+
+        // same nodes, different marker. post-order with an explicit stack:
+        // each entry is a node still being rebuilt, the children it has left
+        // and the ones already retagged
+        fn retag<A, B>(mut node: Node<A>) -> Node<B> {
+            let open = |node: &mut Node<A>| {
+                let children = std::mem::take(&mut node.children);
+                let done = Vec::with_capacity(children.len());
+                (std::mem::take(&mut node.name), children.into_iter(), done)
+            };
+            let mut stack = vec![open(&mut node)];
+
+            loop {
+                let (_, rest, _) = stack.last_mut().expect("the root is popped last");
+                if let Some(mut child) = rest.next() {
+                    stack.push(open(&mut child));
+                    continue;
+                }
+
+                let (name, _, children) = stack.pop().expect("checked above");
+                let node = Node::new(&name, children);
+                match stack.last_mut() {
+                    Some((_, _, done)) => done.push(node),
+                    None => return node,
+                }
+            }
+        }
+
+        // the graph is read by name, as everywhere on GraphNode, so a tree
+        // that repeats a name under two parents (r/a/mod.rs and r/b/mod.rs)
+        // is refused as Shared even if it came from a TreeNode
+        impl TryFrom<GraphNode> for TreeNode {
+            type Error = NotATree;
+
+            fn try_from(node: GraphNode) -> Result<Self, Self::Error> {
+                node.graph().tree_root()?;
+                Ok(retag(node))
+            }
+        }
+
+        // always fine, though a tree that repeats a name comes out as a graph
+        // where those nodes are one shared vertex, and so doesn't convert
+        // back: names are the only vertex identity a GraphNode has
+        impl From<TreeNode> for GraphNode {
+            fn from(node: TreeNode) -> Self {
+                retag(node)
+            }
+        }
+
+        // heights and the diameter count edges, so a lone leaf has height 0.
+        // depths are the shallowest depth a name occurs at
+        #[derive(Debug, Default, PartialEq)]
+        pub struct Shape {
+            pub height: usize,
+            pub diameter: usize,
+            pub max_fanout: usize,
+            // nodes per depth, root first
+            pub histogram: Vec<usize>,
+            // largest height difference between two siblings anywhere
+            pub sibling_spread: usize,
+            pub depths: HashMap<String, usize>,
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+            use crate::stateful::{json, tests::fixture};
+
+            fn graph(name: &str) -> GraphNode {
+                json::from_file(fixture(name)).unwrap()
+            }
+
+            fn names(graph: &Digraph, ids: &[VertexId]) -> Vec<String> {
+                ids.iter().map(|id| graph.name(*id).to_string()).collect()
+            }
+
+            #[test]
+            fn acyclic_fixture() {
+                let node = graph("graph_dag.json");
+                let graph = node.graph();
+
+                assert!(!node.has_cycle());
+                // "util" is shared by "app" and "cli", so it is one vertex
+                assert_eq!(graph.len(), 5);
+
+                let order = graph.topo_sort().unwrap();
+                assert_eq!(
+                    names(&graph, &order),
+                    ["build", "app", "cli", "util", "libc"]
+                );
+
+                let app = graph.id("app").unwrap();
+                let cli = graph.id("cli").unwrap();
+                let libc = graph.id("libc").unwrap();
+                assert!(graph.can_reach(app, libc));
+                assert!(!graph.can_reach(app, cli));
+                assert_eq!(
+                    names(&graph, &graph.reachable(cli)),
+                    ["cli", "util", "libc"]
+                );
+            }
+
+            #[test]
+            fn cyclic_fixture() {
+                let node = graph("graph_cycle.json");
+                let graph = node.graph();
+
+                assert!(node.has_cycle());
+                assert_eq!(graph.topo_sort(), None);
+
+                let cycle = graph.cycle().unwrap();
+                assert_eq!(names(&graph, &cycle), ["b", "c", "d"]);
+
+                let d = graph.id("d").unwrap();
+                let a = graph.id("a").unwrap();
+                assert!(!graph.can_reach(d, a));
+                assert_eq!(graph.reachable(d).len(), 3);
+            }
+
+            #[test]
+            fn built_by_hand() {
+                let mut graph = Digraph::new();
+                let a = graph.vertex("a");
+                let b = graph.vertex("b");
+                graph.add_edge(a, b);
+                assert!(!graph.has_cycle());
+
+                graph.add_edge(b, a);
+                assert!(graph.has_cycle());
+
+                // a tree is trivially acyclic, whatever its marker
+                let tree = GraphNode::new("a", vec![GraphNode::new("b", vec![])]);
+                assert!(!tree.has_cycle());
+
+                let self_loop = GraphNode::new("a", vec![GraphNode::new("a", vec![])]);
+                assert!(self_loop.has_cycle());
+            }
+
+            fn leaf(name: &str) -> TreeNode {
+                TreeNode::new(name, vec![])
+            }
+
+            //        a
+            //      / | \
+            //     b  c  d
+            //    /      |
+            //   e       f
+            //  / \
+            // g   h
+            fn lopsided() -> TreeNode {
+                TreeNode::new(
+                    "a",
+                    vec![
+                        TreeNode::new("b", vec![TreeNode::new("e", vec![leaf("g"), leaf("h")])]),
+                        leaf("c"),
+                        TreeNode::new("d", vec![leaf("f")]),
+                    ],
+                )
+            }
+
+            #[test]
+            fn shape_metrics() {
+                let tree = lopsided();
+                let shape = tree.shape();
+
+                assert_eq!(shape.height, 3);
+                assert_eq!(tree.height(), 3);
+                // g -> e -> b -> a -> d -> f
+                assert_eq!(shape.diameter, 5);
+                assert_eq!(shape.max_fanout, 3);
+                assert_eq!(shape.histogram, [1, 3, 2, 2]);
+                assert_eq!(tree.depth_of("h"), Some(3));
+                assert_eq!(tree.depth_of("a"), Some(0));
+                assert_eq!(tree.depth_of("z"), None);
+
+                assert_eq!(leaf("x").shape().height, 0);
+                assert_eq!(leaf("x").diameter(), 0);
+            }
+
+            #[test]
+            fn diameter_need_not_pass_through_root() {
+                let deep =
+                    |name: &str| TreeNode::new(name, vec![TreeNode::new("y", vec![leaf("z")])]);
+                let tree = TreeNode::new("r", vec![TreeNode::new("x", vec![deep("p"), deep("q")])]);
+
+                // z -> y -> p -> x -> q -> y -> z
+                assert_eq!(tree.diameter(), 6);
+            }
+
+            #[test]
+            fn graph_to_tree() {
+                let graph = GraphNode::new(
+                    "a",
+                    vec![
+                        GraphNode::new("b", vec![GraphNode::new("c", vec![])]),
+                        GraphNode::new("d", vec![]),
+                    ],
+                );
+                let tree = TreeNode::try_from(graph).unwrap();
+                assert_eq!(tree.height(), 2);
+
+                let back = GraphNode::from(tree);
+                assert!(!back.has_cycle());
+                assert_eq!(back.children[0].children[0].name, "c");
+            }
+
+            #[test]
+            fn deep_conversions() {
+                const DEPTH: usize = 100_000;
+                let mut graph = GraphNode::new("0", vec![]);
+                for i in 1..DEPTH {
+                    graph = GraphNode::new(&i.to_string(), vec![graph]);
+                }
+
+                let tree = TreeNode::try_from(graph).unwrap();
+                assert_eq!(tree.height(), DEPTH - 1);
+                assert_eq!(tree.depth_of("0"), Some(DEPTH - 1));
+
+                let back = GraphNode::from(tree);
+                assert_eq!(back.graph().len(), DEPTH);
+            }
+
+            #[test]
+            fn repeated_names_do_not_round_trip() {
+                let module = |dir: &str| TreeNode::new(dir, vec![leaf("mod.rs")]);
+                let tree = TreeNode::new("r", vec![module("a"), module("b")]);
+
+                let graph = GraphNode::from(tree);
+                assert_eq!(graph.graph().len(), 4);
+                assert_eq!(
+                    TreeNode::try_from(graph).unwrap_err(),
+                    NotATree::Shared {
+                        name: "mod.rs".into(),
+                        parents: vec!["a".into(), "b".into()],
+                    }
+                );
+            }
+
+            #[test]
+            fn graph_to_tree_errors() {
+                let err = TreeNode::try_from(graph("graph_cycle.json")).unwrap_err();
+                assert_eq!(
+                    err,
+                    NotATree::Cycle(vec!["b".into(), "c".into(), "d".into()])
+                );
+                assert_eq!(err.to_string(), "cycle through b -> c -> d");
+
+                let err = TreeNode::try_from(graph("graph_dag.json")).unwrap_err();
+                assert_eq!(
+                    err,
+                    NotATree::Shared {
+                        name: "util".into(),
+                        parents: vec!["app".into(), "cli".into()]
+                    }
+                );
+
+                // the root names itself as a child: every vertex has a parent
+                let looped = GraphNode::new("a", vec![GraphNode::new("a", vec![])]);
+                assert!(matches!(
+                    TreeNode::try_from(looped),
+                    Err(NotATree::Cycle(_))
+                ));
+
+                // diamond joined below the root: one root, one shared vertex,
+                // written out in full under both parents
+                let d = || GraphNode::new("d", vec![GraphNode::new("e", vec![])]);
+                let diamond = GraphNode::new(
+                    "a",
+                    vec![
+                        GraphNode::new("b", vec![d()]),
+                        GraphNode::new("c", vec![d()]),
+                    ],
+                );
+                let graph = diamond.graph();
+                let (d, e) = (graph.id("d").unwrap(), graph.id("e").unwrap());
+                assert_eq!(graph.successors(d), [e]);
+                assert_eq!(
+                    TreeNode::try_from(diamond).unwrap_err().to_string(),
+                    "d is shared by b, c"
+                );
+
+                // the same child twice is one edge, not sharing
+                let twice = GraphNode::new(
+                    "a",
+                    vec![GraphNode::new("b", vec![]), GraphNode::new("b", vec![])],
+                );
+                assert_eq!(twice.graph().successors(VertexId(0)).len(), 1);
+                assert!(TreeNode::try_from(twice).is_ok());
+            }
+
+            #[test]
+            fn hand_built_graph_with_two_roots() {
+                let mut graph = Digraph::new();
+                let a = graph.vertex("a");
+                let b = graph.vertex("b");
+                let c = graph.vertex("c");
+                graph.add_edge(a, c);
+                graph.add_edge(b, c);
+
+                let err = graph.tree_root().unwrap_err();
+                assert_eq!(err, NotATree::Roots(vec!["a".into(), "b".into()]));
+                assert_eq!(err.to_string(), "several roots: a, b");
+
+                graph.add_edge(c, a);
+                graph.add_edge(c, b);
+                assert!(matches!(graph.tree_root(), Err(NotATree::Cycle(_))));
+
+                let mut graph = Digraph::new();
+                let a = graph.vertex("a");
+                let b = graph.vertex("b");
+                graph.add_edge(a, b);
+                assert_eq!(graph.tree_root(), Ok(a));
+            }
+
+            #[test]
+            fn balance_criterion() {
+                // b is 2 tall and c is a leaf
+                assert!(!lopsided().balanced());
+                assert!(lopsided().balanced_within(2));
+
+                let even = TreeNode::new("a", vec![TreeNode::new("b", vec![leaf("d")]), leaf("c")]);
+                assert!(even.balanced());
+                assert!(!even.balanced_within(0));
+                assert!(leaf("x").balanced_within(0));
+            }
+        }
+    }
+
+    mod registry {
+        use super::{
+            visitor::{Counter, Index, Merge},
+            Control, Node, Visitor,
+        };
+        use rayon::prelude::*;
+        use std::{
+            any::Any,
+            marker::PhantomData,
+            sync::atomic::{AtomicUsize, Ordering},
+        };
+
+        // where each visitor is in the walk. a visitor that skipped children at
+        // depth d sits out everything deeper than d until the walk comes back
+        // up to d; a stopped visitor sits out the rest of the walk
+        #[derive(Debug, Clone, Copy, PartialEq)]
+        enum Status {
+            Active,
+            Skipping(usize),
+            Stopped,
+        }
+
+        fn dfs_visitors<V>(node: &Node, visitors: &mut [V]) -> Control
+        where
+            V: Visitor<Node>,
+        {
+            let mut status = vec![Status::Active; visitors.len()];
+            dfs_status(node, 0, visitors, &mut status)
+        }
+
+        // dfs_visitors, picking up each visitor's status from the caller and
+        // leaving it there afterwards. `depth` is where `node` is in the tree
+        fn dfs_status<V>(
+            node: &Node,
+            depth: usize,
+            visitors: &mut [V],
+            status: &mut [Status],
+        ) -> Control
+        where
+            V: Visitor<Node>,
+        {
+            let mut stack = vec![(node, depth)];
+
+            while let Some((node, depth)) = stack.pop() {
+                for (visitor, status) in visitors.iter_mut().zip(status.iter_mut()) {
+                    if let Status::Skipping(d) = *status
+                        && depth <= d
+                    {
+                        *status = Status::Active;
+                    }
+
+                    if *status == Status::Active {
+                        match visitor.process_at(node, depth) {
+                            Control::Continue => {}
+                            Control::SkipChildren => *status = Status::Skipping(depth),
+                            Control::Stop => *status = Status::Stopped,
+                        }
+                    }
+                }
+
+                if status.iter().all(|s| *s == Status::Stopped) {
+                    return Control::Stop;
+                }
+
+                // nobody wants this subtree, don't walk it
+                if status.contains(&Status::Active) {
+                    stack.extend(node.children.iter().rev().map(|c| (c, depth + 1)));
+                }
+            }
+
+            Control::Continue
+        }
+
+        fn dfs_without_registry(node: &Node, visitors: &mut [impl Visitor<Node>]) -> Control {
+            dfs_visitors(node, visitors)
+        }
+
+        // a Visitor<Node> that can still be downcast to its concrete type after
+        // being boxed up with visitors of other types. Box<dyn AnyVisitor> is
+        // itself an AnyVisitor, so deref to the inner one before calling as_any
+        trait AnyVisitor: Visitor<Node> {
+            fn as_any(&self) -> &dyn Any;
+            fn as_any_mut(&mut self) -> &mut dyn Any;
+        }
+
+        impl<V: Visitor<Node> + 'static> AnyVisitor for V {
+            fn as_any(&self) -> &dyn Any {
+                self
+            }
+
+            fn as_any_mut(&mut self) -> &mut dyn Any {
+                self
+            }
+        }
+
+        // closures being visitors rules out a blanket impl for Box<V> (a boxed
+        // closure is a closure too), so the boxes the registry keeps forward
+        // by hand
+        impl Visitor<Node> for Box<dyn AnyVisitor> {
+            fn process(&mut self, node: &Node) -> Control {
+                (**self).process(node)
+            }
+
+            fn process_at(&mut self, node: &Node, depth: usize) -> Control {
+                (**self).process_at(node, depth)
+            }
+
+            fn enter_at(&mut self, node: &Node, depth: usize) -> Control {
+                (**self).enter_at(node, depth)
+            }
+
+            fn leave_at(&mut self, node: &Node, depth: usize) {
+                (**self).leave_at(node, depth)
+            }
+
+            fn enter(&mut self, node: &Node) -> Control {
+                (**self).enter(node)
+            }
+
+            fn leave(&mut self, node: &Node) {
+                (**self).leave(node)
+            }
+        }
+
+        impl Visitor<Node> for Box<dyn Visitor<Node>> {
+            fn process(&mut self, node: &Node) -> Control {
+                (**self).process(node)
+            }
+
+            fn process_at(&mut self, node: &Node, depth: usize) -> Control {
+                (**self).process_at(node, depth)
+            }
+
+            fn enter_at(&mut self, node: &Node, depth: usize) -> Control {
+                (**self).enter_at(node, depth)
+            }
+
+            fn leave_at(&mut self, node: &Node, depth: usize) {
+                (**self).leave_at(node, depth)
+            }
+
+            fn enter(&mut self, node: &Node) -> Control {
+                (**self).enter(node)
+            }
+
+            fn leave(&mut self, node: &Node) {
+                (**self).leave(node)
+            }
+        }
+
+        // which builder a handle came from. every builder gets a fresh one,
+        // so a handle can't read a slot of the same type in someone else's
+        // registry
+        #[derive(Debug, Clone, Copy, PartialEq)]
+        struct Owner(usize);
+
+        impl Default for Owner {
+            fn default() -> Self {
+                static NEXT: AtomicUsize = AtomicUsize::new(0);
+                Owner(NEXT.fetch_add(1, Ordering::Relaxed))
+            }
+        }
+
+        // typed ticket for a visitor handed to RegistryBuilder::add, redeemed
+        // with Registry::get once the walk is done
+        struct Handle<V> {
+            owner: Owner,
+            index: usize,
+            _marker: PhantomData<fn() -> V>,
+        }
+
+        // derive would want V: Clone
+        impl<V> Clone for Handle<V> {
+            fn clone(&self) -> Self {
+                *self
+            }
+        }
+
+        impl<V> Copy for Handle<V> {}
+
+        struct Registry {
+            owner: Owner,
+            node: Node,
+            visitors: Vec<Box<dyn AnyVisitor>>,
+        }
+
+        #[derive(Default)]
+        struct RegistryBuilder {
+            owner: Owner,
+            visitors: Vec<Box<dyn AnyVisitor>>,
+        }
+
+        impl RegistryBuilder {
+            fn new() -> Self {
+                RegistryBuilder::default()
+            }
+
+            fn visitor(mut self, visitor: Box<dyn Visitor<Node>>) -> Self {
+                self.visitors.push(Box::new(visitor));
+                self
+            }
+
+            // interface cleanup: without boxed value being passed in
+            fn add_visitor<V>(mut self, visitor: V) -> Self
+            where
+                V: Visitor<Node> + 'static,
+            {
+                self.visitors.push(Box::new(visitor));
+                self
+            }
+
+            // like add_visitor, but hands back a handle to read the visitor's
+            // state out of the built registry
+            fn add<V>(&mut self, visitor: V) -> Handle<V>
+            where
+                V: Visitor<Node> + 'static,
+            {
+                self.visitors.push(Box::new(visitor));
+                Handle {
+                    owner: self.owner,
+                    index: self.visitors.len() - 1,
+                    _marker: PhantomData,
+                }
+            }
+
+            fn build(self, node: Node) -> Registry {
+                Registry {
+                    owner: self.owner,
+                    node,
+                    visitors: self.visitors,
+                }
+            }
+        }
+
+        impl Registry {
+            // walks the registry's own node with every visitor
+            fn run(&mut self) -> Control {
+                dfs_visitors(&self.node, &mut self.visitors)
+            }
+
+            // panics if the handle came from a different builder
+            fn get<V: 'static>(&self, handle: Handle<V>) -> &V {
+                assert_eq!(
+                    handle.owner, self.owner,
+                    "handle belongs to another registry"
+                );
+                self.visitors
+                    .get(handle.index)
+                    .and_then(|v| (**v).as_any().downcast_ref())
+                    .expect("a handle's slot holds its type")
+            }
+
+            fn get_mut<V: 'static>(&mut self, handle: Handle<V>) -> &mut V {
+                assert_eq!(
+                    handle.owner, self.owner,
+                    "handle belongs to another registry"
+                );
+                self.visitors
+                    .get_mut(handle.index)
+                    .and_then(|v| (**v).as_any_mut().downcast_mut())
+                    .expect("a handle's slot holds its type")
+            }
+        }
+
+        fn dfs_with_registry(node: &Node, registry: &mut Registry) -> Control {
+            dfs_visitors(node, &mut registry.visitors)
+        }
+
+        // an AnyVisitor that can be split up and merged back, so it can be
+        // sent down several sibling subtrees at once
+        trait ParVisitor: AnyVisitor + Send + Sync {
+            fn split_boxed(&self) -> Box<dyn ParVisitor>;
+            fn merge_boxed(&mut self, later: Box<dyn ParVisitor>);
+            fn into_any(self: Box<Self>) -> Box<dyn Any>;
+        }
+
+        impl<V: Visitor<Node> + Merge + Send + Sync + 'static> ParVisitor for V {
+            fn split_boxed(&self) -> Box<dyn ParVisitor> {
+                Box::new(self.split())
+            }
+
+            // `later` always comes from split_boxed on the same visitor
+            fn merge_boxed(&mut self, later: Box<dyn ParVisitor>) {
+                let later = later.into_any().downcast::<V>();
+                self.merge(*later.expect("merging visitors of different types"));
+            }
+
+            fn into_any(self: Box<Self>) -> Box<dyn Any> {
+                self
+            }
+        }
+
+        impl Visitor<Node> for &mut (dyn ParVisitor + '_) {
+            fn process(&mut self, node: &Node) -> Control {
+                (**self).process(node)
+            }
+
+            fn process_at(&mut self, node: &Node, depth: usize) -> Control {
+                (**self).process_at(node, depth)
+            }
+
+            fn enter_at(&mut self, node: &Node, depth: usize) -> Control {
+                (**self).enter_at(node, depth)
+            }
+
+            fn leave_at(&mut self, node: &Node, depth: usize) {
+                (**self).leave_at(node, depth)
+            }
+
+            fn enter(&mut self, node: &Node) -> Control {
+                (**self).enter(node)
+            }
+
+            fn leave(&mut self, node: &Node) {
+                (**self).leave(node)
+            }
+        }
+
+        // below this depth sibling subtrees are walked in parallel, past it
+        // each subtree is walked serially by whichever thread reached it.
+        // keeps recursion shallow on deep chains
+        const PAR_DEPTH: usize = 16;
+
+        // what one thread made of a run of sibling subtrees: a visitor per
+        // slot (None if it sat the run out) and whether it stopped in it
+        struct Part {
+            visitors: Vec<Option<Box<dyn ParVisitor>>>,
+            stopped: Vec<bool>,
+        }
+
+        impl Part {
+            fn split(visitors: &[Option<&mut dyn ParVisitor>], active: &[bool]) -> Part {
+                Part {
+                    visitors: visitors
+                        .iter()
+                        .zip(active)
+                        .map(|(v, on)| v.as_ref().filter(|_| *on).map(|v| v.split_boxed()))
+                        .collect(),
+                    stopped: vec![false; visitors.len()],
+                }
+            }
+
+            fn walk(mut self, node: &Node, depth: usize) -> Part {
+                let mut visitors: Vec<_> = self
+                    .visitors
+                    .iter_mut()
+                    .zip(&self.stopped)
+                    .map(|(v, stopped)| match v {
+                        Some(v) if !stopped => Some(&mut **v as &mut dyn ParVisitor),
+                        _ => None,
+                    })
+                    .collect();
+                let stopped = par_walk(node, depth, &mut visitors);
+                for (s, now) in self.stopped.iter_mut().zip(stopped) {
+                    *s |= now;
+                }
+                self
+            }
+
+            // self's siblings come before later's. whatever a visitor saw
+            // after it stopped is thrown away, as if never walked
+            fn then(mut self, later: Part) -> Part {
+                let slots = self.visitors.iter_mut().zip(self.stopped.iter_mut());
+                for ((v, stopped), (next, next_stopped)) in
+                    slots.zip(later.visitors.into_iter().zip(later.stopped))
+                {
+                    if *stopped {
+                        continue;
+                    }
+                    match (v.as_mut(), next) {
+                        (Some(v), Some(next)) => v.merge_boxed(next),
+                        (None, next) => *v = next,
+                        (Some(_), None) => {}
+                    }
+                    *stopped = next_stopped;
+                }
+                self
+            }
+        }
+
+        // walks `node`'s subtree with the visitors still active going in
+        // (None for the rest), returning which of them stopped in it
+        fn par_walk(
+            node: &Node,
+            depth: usize,
+            visitors: &mut [Option<&mut dyn ParVisitor>],
+        ) -> Vec<bool> {
+            if depth >= PAR_DEPTH {
+                let (slots, mut present): (Vec<usize>, Vec<_>) = visitors
+                    .iter_mut()
+                    .enumerate()
+                    .filter_map(|(i, v)| Some((i, v.as_deref_mut()?)))
+                    .unzip();
+                let mut status = vec![Status::Active; present.len()];
+                dfs_status(node, depth, &mut present, &mut status);
+
+                let mut stopped = vec![false; visitors.len()];
+                for (i, s) in slots.into_iter().zip(status) {
+                    stopped[i] = s == Status::Stopped;
+                }
+                return stopped;
+            }
+
+            let mut stopped = vec![false; visitors.len()];
+            let mut active = vec![false; visitors.len()];
+            for (i, v) in visitors.iter_mut().enumerate() {
+                if let Some(v) = v {
+                    match v.process_at(node, depth) {
+                        Control::Continue => active[i] = true,
+                        Control::SkipChildren => {}
+                        Control::Stop => stopped[i] = true,
+                    }
+                }
+            }
+
+            if !active.contains(&true) {
+                return stopped;
+            }
+
+            let part = node
+                .children
+                .par_iter()
+                .fold(
+                    || Part::split(visitors, &active),
+                    |part, child| part.walk(child, depth + 1),
+                )
+                .reduce(
+                    || Part {
+                        visitors: (0..visitors.len()).map(|_| None).collect(),
+                        stopped: vec![false; visitors.len()],
+                    },
+                    Part::then,
+                );
+
+            for ((v, s), (part, part_stopped)) in visitors
+                .iter_mut()
+                .zip(stopped.iter_mut())
+                .zip(part.visitors.into_iter().zip(part.stopped))
+            {
+                if let (Some(v), Some(part)) = (v, part) {
+                    v.merge_boxed(part);
+                }
+                *s |= part_stopped;
+            }
+            stopped
+        }
+
+        // Registry for visitors that implement Merge. run visits sibling
+        // subtrees on the rayon pool and ends up with exactly the state a
+        // serial dfs_with_registry would have left behind
+        struct ParRegistry {
+            owner: Owner,
+            node: Node,
+            visitors: Vec<Box<dyn ParVisitor>>,
+        }
+
+        #[derive(Default)]
+        struct ParRegistryBuilder {
+            owner: Owner,
+            visitors: Vec<Box<dyn ParVisitor>>,
+        }
+
+        impl ParRegistryBuilder {
+            fn new() -> Self {
+                ParRegistryBuilder::default()
+            }
+
+            fn add<V>(&mut self, visitor: V) -> Handle<V>
+            where
+                V: Visitor<Node> + Merge + Send + Sync + 'static,
+            {
+                self.visitors.push(Box::new(visitor));
+                Handle {
+                    owner: self.owner,
+                    index: self.visitors.len() - 1,
+                    _marker: PhantomData,
+                }
+            }
+
+            fn build(self, node: Node) -> ParRegistry {
+                ParRegistry {
+                    owner: self.owner,
+                    node,
+                    visitors: self.visitors,
+                }
+            }
+        }
+
+        impl ParRegistry {
+            fn run(&mut self) -> Control {
+                let mut visitors: Vec<_> = self
+                    .visitors
+                    .iter_mut()
+                    .map(|v| Some(&mut **v as &mut dyn ParVisitor))
+                    .collect();
+
+                if par_walk(&self.node, 0, &mut visitors).iter().all(|s| *s) {
+                    Control::Stop
+                } else {
+                    Control::Continue
+                }
+            }
+
+            // panics if the handle came from a different builder
+            fn get<V: 'static>(&self, handle: Handle<V>) -> &V {
+                assert_eq!(
+                    handle.owner, self.owner,
+                    "handle belongs to another registry"
+                );
+                self.visitors
+                    .get(handle.index)
+                    .and_then(|v| (**v).as_any().downcast_ref())
+                    .expect("a handle's slot holds its type")
+            }
+        }
+
+        #[derive(Debug)]
+        struct Printer;
+
+        impl Visitor<Node> for Printer {
+            fn process(&mut self, node: &Node) -> Control {
+                println!("{:#?}", node);
+                Control::Continue
+            }
+        }
+
+        fn visit_all(node: Node) {
+            let mut rb = RegistryBuilder::new()
+                .visitor(Box::new(Printer))
+                .visitor(Box::new(Counter::default()))
+                .visitor(Box::new(Index::default()))
+                .build(node.clone());
+
+            dfs_with_registry(&node, &mut rb);
+
+            let mut rb = RegistryBuilder::new()
+                .add_visitor(Printer)
+                .add_visitor(Counter::default())
+                .add_visitor(Index::default())
+                .build(node.clone());
+
+            dfs_with_registry(&node, &mut rb);
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+
+            struct Recorder {
+                skip: &'static str,
+                stop: &'static str,
+                seen: Vec<String>,
+            }
+
+            impl Recorder {
+                fn new(skip: &'static str, stop: &'static str) -> Self {
+                    Recorder {
+                        skip,
+                        stop,
+                        seen: vec![],
+                    }
+                }
+            }
+
+            impl Visitor<Node> for Recorder {
+                fn process(&mut self, node: &Node) -> Control {
+                    self.seen.push(node.name.clone());
+                    if node.name == self.stop {
+                        Control::Stop
+                    } else if node.name == self.skip {
+                        Control::SkipChildren
+                    } else {
+                        Control::Continue
+                    }
+                }
+            }
+
+            impl Merge for Recorder {
+                fn split(&self) -> Self {
+                    Recorder::new(self.skip, self.stop)
+                }
+
+                fn merge(&mut self, later: Self) {
+                    self.seen.extend(later.seen);
+                }
+            }
+
+            #[test]
+            fn control_is_per_visitor() {
+                let node = Node::new(
+                    "a",
+                    vec![
+                        Node::new("b", vec![Node::new("c", vec![])]),
+                        Node::new("d", vec![Node::new("e", vec![])]),
+                    ],
+                );
+                let mut visitors = vec![
+                    Recorder::new("", ""),
+                    Recorder::new("b", ""),
+                    Recorder::new("", "c"),
+                ];
+
+                assert_eq!(
+                    dfs_without_registry(&node, &mut visitors),
+                    Control::Continue
+                );
+                assert_eq!(visitors[0].seen, ["a", "b", "c", "d", "e"]);
+                assert_eq!(visitors[1].seen, ["a", "b", "d", "e"]);
+                assert_eq!(visitors[2].seen, ["a", "b", "c"]);
+            }
+
+            fn sample() -> Node {
+                Node::new(
+                    "a",
+                    vec![
+                        Node::new("b", vec![Node::new("c", vec![])]),
+                        Node::new("d", vec![Node::new("e", vec![])]),
+                    ],
+                )
+            }
+
+            #[test]
+            fn typed_results() {
+                let mut builder = RegistryBuilder::new().add_visitor(Recorder::new("", ""));
+                let counter = builder.add(Counter::default());
+                let index = builder.add(Index::default());
+                let skipper = builder.add(Recorder::new("b", ""));
+                let mut registry = builder.build(sample());
+
+                assert_eq!(registry.run(), Control::Continue);
+
+                let counter = registry.get(counter);
+                assert_eq!((counter.total, counter.leaves), (5, 2));
+                assert!(registry.get(index).names.contains("e"));
+                assert_eq!(registry.get(skipper).seen, ["a", "b", "d", "e"]);
+
+                registry.get_mut(skipper).seen.clear();
+                assert!(registry.get(skipper).seen.is_empty());
+            }
+
+            #[test]
+            #[should_panic(expected = "handle belongs to another registry")]
+            fn foreign_handle() {
+                let mut builder = RegistryBuilder::new();
+                builder.add(Index::default());
+                let counter = builder.add(Counter::default());
+
+                let registry = RegistryBuilder::new()
+                    .add_visitor(Index::default())
+                    .build(sample());
+                registry.get(counter);
+            }
+
+            // same slot, same type, still not this registry's
+            #[test]
+            #[should_panic(expected = "handle belongs to another registry")]
+            fn foreign_handle_of_the_same_type() {
+                let counter = RegistryBuilder::new().add(Counter::default());
+
+                let mut builder = RegistryBuilder::new();
+                builder.add(Counter::default());
+                let registry = builder.build(sample());
+                registry.get(counter);
+            }
+
+            #[test]
+            fn million_deep_chain() {
+                let node = crate::stateful::tests::chain(1_000_000);
+                let mut builder = RegistryBuilder::new();
+                let counter = builder.add(Counter::default());
+                let mut registry = builder.build(Node::new("unused", vec![]));
+
+                assert_eq!(dfs_with_registry(&node, &mut registry), Control::Continue);
+                assert_eq!(registry.get(counter).total, 1_000_000);
+
+                let mut visitors = vec![Recorder::new("", "")];
+                dfs_without_registry(&node, &mut visitors);
+                assert_eq!(visitors[0].seen.len(), 1_000_000);
+            }
+
+            #[test]
+            fn parallel_matches_serial() {
+                let node = crate::stateful::tests::wide(6, 5);
+                let recorders = || {
+                    [
+                        Recorder::new("", ""),
+                        Recorder::new("r.1", "r.4.2.0"),
+                        Recorder::new("r.2.3", "r.0.0"),
+                    ]
+                };
+
+                let mut builder = RegistryBuilder::new();
+                let counter = builder.add(Counter::default());
+                let index = builder.add(Index::default());
+                let serial: Vec<_> = recorders().map(|r| builder.add(r)).into();
+                let mut registry = builder.build(node.clone());
+                assert_eq!(registry.run(), Control::Continue);
+
+                let mut builder = ParRegistryBuilder::new();
+                let par_counter = builder.add(Counter::default());
+                let par_index = builder.add(Index::default());
+                let parallel: Vec<_> = recorders().map(|r| builder.add(r)).into();
+                let mut par_registry = builder.build(node);
+                assert_eq!(par_registry.run(), Control::Continue);
+
+                let (a, b) = (registry.get(counter), par_registry.get(par_counter));
+                assert_eq!((a.total, a.leaves), (b.total, b.leaves));
+                assert_eq!(registry.get(index).names, par_registry.get(par_index).names);
+                for (a, b) in serial.into_iter().zip(parallel) {
+                    assert_eq!(registry.get(a).seen, par_registry.get(b).seen);
+                }
+            }
+
+            #[test]
+            fn parallel_stops_when_every_visitor_has() {
+                let mut builder = ParRegistryBuilder::new();
+                let first = builder.add(Recorder::new("", "r.0.0"));
+                let second = builder.add(Recorder::new("", "r.1"));
+                let mut registry = builder.build(crate::stateful::tests::wide(3, 3));
+
+                assert_eq!(registry.run(), Control::Stop);
+                assert_eq!(registry.get(first).seen, ["r", "r.0", "r.0.0"]);
+                assert_eq!(registry.get(second).seen.last().unwrap(), "r.1");
+            }
+
+            #[test]
+            fn parallel_million_nodes() {
+                let mut builder = ParRegistryBuilder::new();
+                let counter = builder.add(Counter::default());
+                let mut registry = builder.build(crate::stateful::tests::wide(1000, 2));
+                registry.run();
+
+                let counter = registry.get(counter);
+                assert_eq!((counter.total, counter.leaves), (1_001_001, 1_000_000));
+
+                let mut builder = ParRegistryBuilder::new();
+                let counter = builder.add(Counter::default());
+                let mut registry = builder.build(crate::stateful::tests::chain(1_000_000));
+                registry.run();
+                assert_eq!(registry.get(counter).total, 1_000_000);
+            }
+
+            #[test]
+            fn stops_when_every_visitor_has() {
+                let node = Node::new("a", vec![Node::new("b", vec![]), Node::new("c", vec![])]);
+                let mut visitors = vec![Recorder::new("", "a"), Recorder::new("", "b")];
+
+                assert_eq!(dfs_without_registry(&node, &mut visitors), Control::Stop);
+                assert_eq!(visitors[1].seen, ["a", "b"]);
+            }
+        }
+    }
+
+    mod fold {
+        use super::{nodetype::CoolNode, Node};
+        use std::borrow::Cow;
+
+        // a rewriting visitor. `fold` sees each node once its children have
+        // been folded and returns whatever should stand in its place: no nodes
+        // drops the subtree, one renames or replaces it, several are spliced
+        // into the parent in order
+        pub trait Fold<N> {
+            type Out;
+
+            fn fold(&mut self, node: N, children: Vec<Self::Out>) -> Vec<Self::Out>;
+        }
+
+        // consumes the tree. each node is handed over with its children
+        // already moved out, so untouched names and vecs are reused rather
+        // than cloned
+        pub fn fold<F>(root: Node, f: &mut F) -> Vec<F::Out>
+        where
+            F: Fold<Node> + ?Sized,
+        {
+            enum Frame {
+                Open(Node),
+                // node with its children taken out, and how many it had
+                Close(Node, usize),
+            }
+
+            // one entry per finished subtree, in visiting order
+            let mut done: Vec<Vec<F::Out>> = vec![];
+            let mut stack = vec![Frame::Open(root)];
+
+            while let Some(frame) = stack.pop() {
+                match frame {
+                    Frame::Open(mut node) => {
+                        let children = std::mem::take(&mut node.children);
+                        stack.push(Frame::Close(node, children.len()));
+                        stack.extend(children.into_iter().rev().map(Frame::Open));
+                    }
+                    Frame::Close(node, n) => {
+                        let children = done.split_off(done.len() - n).into_iter().flatten();
+                        done.push(f.fold(node, children.collect()));
+                    }
+                }
+            }
+
+            done.pop().unwrap_or_default()
+        }
+
+        // borrows the tree. with `Out = Cow<Node>` and `share` below, a tree
+        // the fold leaves alone comes back borrowed instead of copied
+        pub fn fold_ref<'a, F>(root: &'a Node, f: &mut F) -> Vec<F::Out>
+        where
+            F: Fold<&'a Node> + ?Sized,
+        {
+            let mut done: Vec<Vec<F::Out>> = vec![];
+            let mut stack = vec![(root, false)];
+
+            while let Some((node, expanded)) = stack.pop() {
+                if expanded {
+                    let n = node.children.len();
+                    let children = done.split_off(done.len() - n).into_iter().flatten();
+                    done.push(f.fold(node, children.collect()));
+                } else {
+                    stack.push((node, true));
+                    stack.extend(node.children.iter().rev().map(|c| (c, false)));
+                }
+            }
+
+            done.pop().unwrap_or_default()
+        }
+
+        // `node` itself if the name and folded children are exactly what it
+        // already had, otherwise a new node. a Node owns its children, so
+        // the new one gets copies of any that came back borrowed: the saving
+        // is only for subtrees with no change anywhere above them either
+        pub fn share<'a>(
+            node: &'a Node,
+            name: &str,
+            children: Vec<Cow<'a, Node>>,
+        ) -> Cow<'a, Node> {
+            let unchanged = name == node.name
+                && children.len() == node.children.len()
+                && children
+                    .iter()
+                    .zip(&node.children)
+                    .all(|(new, old)| match new {
+                        Cow::Borrowed(new) => std::ptr::eq(*new, old),
+                        Cow::Owned(_) => false,
+                    });
+
+            if unchanged {
+                Cow::Borrowed(node)
+            } else {
+                let children = children.into_iter().map(Cow::into_owned).collect();
+                let mut new = Node::new(name, children);
+                new.size = node.size;
+                Cow::Owned(new)
+            }
+        }
+
+        pub struct Rename<F>(pub F);
+
+        impl<F: FnMut(&str) -> String> Fold<Node> for Rename<F> {
+            type Out = Node;
+
+            fn fold(&mut self, mut node: Node, children: Vec<Node>) -> Vec<Node> {
+                node.name = (self.0)(&node.name);
+                node.children = children;
+                vec![node]
+            }
+        }
+
+        // same as Rename(str::to_lowercase) but over a borrowed tree, which
+        // comes back as is if it is already lower case
+        pub struct Lowercase;
+
+        impl<'a> Fold<&'a Node> for Lowercase {
+            type Out = Cow<'a, Node>;
+
+            fn fold(&mut self, node: &'a Node, children: Vec<Self::Out>) -> Vec<Self::Out> {
+                vec![share(node, &node.name.to_lowercase(), children)]
+            }
+        }
+
+        // drops every node (and what is left of its subtree) the predicate
+        // holds for. bottom up, so a parent emptied by pruning can be pruned
+        // in the same pass
+        pub struct Prune<P>(pub P);
+
+        impl<P: FnMut(&Node) -> bool> Fold<Node> for Prune<P> {
+            type Out = Node;
+
+            fn fold(&mut self, mut node: Node, children: Vec<Node>) -> Vec<Node> {
+                node.children = children;
+                if (self.0)(&node) {
+                    vec![]
+                } else {
+                    vec![node]
+                }
+            }
+        }
+
+        // replaces every node the predicate holds for with its children
+        pub struct Splice<P>(pub P);
+
+        impl<P: FnMut(&Node) -> bool> Fold<Node> for Splice<P> {
+            type Out = Node;
+
+            fn fold(&mut self, mut node: Node, children: Vec<Node>) -> Vec<Node> {
+                if (self.0)(&node) {
+                    children
+                } else {
+                    node.children = children;
+                    vec![node]
+                }
+            }
+        }
+
+        // maps a Node tree onto CoolNode, deriving each nick from the name
+        pub struct ToCool<F>(pub F);
+
+        impl<F: FnMut(&str) -> String> Fold<Node> for ToCool<F> {
+            type Out = CoolNode;
+
+            fn fold(&mut self, mut node: Node, children: Vec<CoolNode>) -> Vec<CoolNode> {
+                let nick = (self.0)(&node.name);
+                vec![CoolNode {
+                    // Node's Drop keeps the field from being moved out
+                    name: std::mem::take(&mut node.name),
+                    nick,
+                    children,
+                }]
+            }
+        }
+
+        // node -> node folds run one after another, each over the whole
+        // output of the one before
+        #[derive(Default)]
+        pub struct Pipeline {
+            passes: Vec<Box<dyn Fold<Node, Out = Node>>>,
+        }
+
+        impl Pipeline {
+            pub fn new() -> Self {
+                Pipeline::default()
+            }
+
+            pub fn pass<F>(mut self, pass: F) -> Self
+            where
+                F: Fold<Node, Out = Node> + 'static,
+            {
+                self.passes.push(Box::new(pass));
+                self
+            }
+
+            pub fn run(&mut self, root: Node) -> Vec<Node> {
+                let mut roots = vec![root];
+                for pass in self.passes.iter_mut() {
+                    roots = roots
+                        .into_iter()
+                        .flat_map(|root| fold(root, &mut **pass))
+                        .collect();
+                }
+                roots
+            }
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+
+            fn tree() -> Node {
+                Node::new(
+                    "Root",
+                    vec![
+                        Node::new("src/", vec![Node::new("Main.rs", vec![])]),
+                        Node::new("empty/", vec![Node::new("nested/", vec![])]),
+                        Node::new("lib/", vec![Node::new("a", vec![]), Node::new("b", vec![])]),
+                    ],
+                )
+            }
+
+            fn names(node: &Node) -> Vec<&str> {
+                node.iter_pre().map(|n| n.name.as_str()).collect()
+            }
+
+            fn is_empty_dir(node: &Node) -> bool {
+                node.name.ends_with('/') && node.children.is_empty()
+            }
+
+            #[test]
+            fn rename() {
+                let out = fold(tree(), &mut Rename(|name: &str| name.to_lowercase()));
+                assert_eq!(
+                    names(&out[0]),
+                    ["root", "src/", "main.rs", "empty/", "nested/", "lib/", "a", "b"]
+                );
+            }
+
+            #[test]
+            fn prune_cascades() {
+                let out = fold(tree(), &mut Prune(is_empty_dir));
+                assert_eq!(
+                    names(&out[0]),
+                    ["Root", "src/", "Main.rs", "lib/", "a", "b"]
+                );
+            }
+
+            #[test]
+            fn splice_and_drop_root() {
+                let out = fold(tree(), &mut Splice(|n: &Node| n.name == "lib/"));
+                assert_eq!(
+                    names(&out[0]),
+                    ["Root", "src/", "Main.rs", "empty/", "nested/", "a", "b"]
+                );
+
+                let out = fold(tree(), &mut Prune(|n: &Node| n.name == "Root"));
+                assert!(out.is_empty());
+
+                let out = fold(tree(), &mut Splice(|n: &Node| n.name == "Root"));
+                assert_eq!(out.len(), 3);
+            }
+
+            #[test]
+            fn to_cool_node() {
+                let out = fold(tree(), &mut ToCool(|name: &str| name.to_uppercase()));
+                let cool = &out[0];
+                assert_eq!((cool.name.as_str(), cool.nick.as_str()), ("Root", "ROOT"));
+                assert_eq!(cool.children[0].children[0].nick, "MAIN.RS");
+            }
+
+            #[test]
+            fn borrowed_fold_shares_unchanged_trees() {
+                let root = tree();
+                let out = fold_ref(&root, &mut Lowercase);
+                let new = out[0].as_ref();
+
+                assert!(matches!(out[0], Cow::Owned(_)));
+                assert_eq!(names(new)[..3], ["root", "src/", "main.rs"]);
+                // lib/ didn't change, but its new parent needs a copy of it
+                let lib = &root.children[2];
+                assert_eq!(lib.name, "lib/");
+                assert_eq!(&new.children[2], lib);
+                assert!(!std::ptr::eq(&new.children[2], lib));
+
+                // folded on its own, nothing changes and nothing is copied
+                let out = fold_ref(lib, &mut Lowercase);
+                assert!(matches!(out[0], Cow::Borrowed(node) if std::ptr::eq(node, lib)));
+            }
+
+            #[test]
+            fn pipeline() {
+                let out = Pipeline::new()
+                    .pass(Prune(is_empty_dir))
+                    .pass(Rename(|name: &str| name.to_lowercase()))
+                    .pass(Splice(|n: &Node| n.name == "src/"))
+                    .run(tree());
+
+                assert_eq!(names(&out[0]), ["root", "main.rs", "lib/", "a", "b"]);
+            }
+
+            #[test]
+            fn million_deep_chain() {
+                let root = crate::stateful::tests::chain(1_000_000);
+                let out = fold(root, &mut Rename(|name: &str| name.to_uppercase()));
+                assert_eq!(out[0].iter_pre().count(), 1_000_000);
+            }
+        }
+    }
+
+    mod json {
+        use serde::de::DeserializeOwned;
+        use std::{fmt, fs, io, path::Path};
+
+        #[derive(Debug)]
+        pub enum LoadError {
+            Io(io::Error),
+            // `path` points at the offending entry, e.g. `children[1].name`
+            Json {
+                path: String,
+                error: serde_json::Error,
+            },
+        }
+
+        impl fmt::Display for LoadError {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                match self {
+                    LoadError::Io(e) => write!(f, "{}", e),
+                    LoadError::Json { path, error } => write!(f, "{}: {}", path, error),
+                }
+            }
+        }
+
+        impl std::error::Error for LoadError {}
+
+        impl From<io::Error> for LoadError {
+            fn from(e: io::Error) -> Self {
+                LoadError::Io(e)
+            }
+        }
+
+        // works for any of the tree types: Node, CoolNode, typestate::Node<T>
+        pub fn from_str<T: DeserializeOwned>(json: &str) -> Result<T, LoadError> {
+            let de = &mut serde_json::Deserializer::from_str(json);
+
+            serde_path_to_error::deserialize(de).map_err(|e| LoadError::Json {
+                path: e.path().to_string(),
+                error: e.into_inner(),
+            })
+        }
+
+        pub fn from_file<T: DeserializeOwned>(path: impl AsRef<Path>) -> Result<T, LoadError> {
+            from_str(&fs::read_to_string(path)?)
+        }
+
+        pub fn to_string<T: serde::Serialize>(tree: &T) -> serde_json::Result<String> {
+            serde_json::to_string_pretty(tree)
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::super::{nodetype::CoolNode, tests::fixture, typestate, Node};
+            use super::*;
+
+            // load -> save -> load -> save must be stable
+            fn round_trip<T>(json: &str) -> String
+            where
+                T: DeserializeOwned + serde::Serialize,
+            {
+                let first = to_string(&from_str::<T>(json).unwrap()).unwrap();
+                let second = to_string(&from_str::<T>(&first).unwrap()).unwrap();
+                assert_eq!(first, second);
+                first
+            }
+
+            #[test]
+            fn node_from_fixture() {
+                let node: Node = from_file(fixture("tree.json")).unwrap();
+                let names: Vec<&str> = node.iter_pre().map(|n| n.name.as_str()).collect();
+                assert_eq!(names, ["root", "src", "main.rs", "lib.rs", "README.md"]);
+
+                round_trip::<Node>(&fs::read_to_string(fixture("tree.json")).unwrap());
+            }
+
+            #[test]
+            fn cool_node_round_trip() {
+                let json = r#"{"name": "a", "nick": "A", "children": [
+                    {"name": "b", "nick": "B", "children": []}
+                ]}"#;
+                let out = round_trip::<CoolNode>(json);
+                assert!(out.contains(r#""nick": "B""#));
+            }
+
+            #[test]
+            fn typestate_node_skips_marker() {
+                let json = r#"{"name": "a", "children": [{"name": "b", "children": []}]}"#;
+                let out = round_trip::<typestate::TreeNode>(json);
+                assert!(!out.contains("marker"));
+                round_trip::<typestate::GraphNode>(json);
+            }
+
+            #[test]
+            fn reports_path_of_bad_entry() {
+                let json = r#"{"name": "a", "children": [
+                    {"name": "b", "children": []},
+                    {"name": "c", "children": [{"name": 7, "children": []}]}
+                ]}"#;
+
+                match from_str::<Node>(json) {
+                    Err(LoadError::Json { path, .. }) => {
+                        assert_eq!(path, "children[1].children[0].name")
+                    }
+                    other => panic!("expected a json error, got {:?}", other),
+                }
+
+                let err = from_file::<Node>(fixture("malformed.json")).unwrap_err();
+                assert!(err.to_string().starts_with("children[0].children: "));
+            }
+        }
+    }
+
+    mod arena {
+        use super::Node;
+
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub struct NodeId(usize);
+
+        #[derive(Debug, Clone)]
+        struct Slot {
+            name: String,
+            size: Option<u64>,
+            parent: Option<NodeId>,
+            children: Vec<NodeId>,
+        }
+
+        // every node lives in one vec and links to the others by index, so a
+        // node knows its parent and moving a subtree is relinking two parents
+        // instead of cloning it. detached subtrees keep their slots; ids stay
+        // valid for the life of the tree
+        #[derive(Debug, Clone)]
+        pub struct Tree {
+            nodes: Vec<Slot>,
+            root: NodeId,
+        }
+
+        #[derive(Debug, PartialEq)]
+        pub enum MoveError {
+            // the root has no parent to be detached from
+            Root,
+            // only detached subtrees can be reattached
+            Attached(NodeId),
+            // the new parent is inside the subtree being moved
+            IntoItself,
+            Index(usize),
+        }
+
+        impl std::fmt::Display for MoveError {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                match self {
+                    MoveError::Root => write!(f, "the root can't be moved"),
+                    MoveError::Attached(id) => write!(f, "{:?} is still attached", id),
+                    MoveError::IntoItself => write!(f, "can't move a subtree into itself"),
+                    MoveError::Index(i) => write!(f, "no room for a child at {}", i),
+                }
+            }
+        }
+
+        impl std::error::Error for MoveError {}
+
+        impl Tree {
+            pub fn new(name: &str) -> Self {
+                Tree {
+                    nodes: vec![Slot {
+                        name: name.into(),
+                        size: None,
+                        parent: None,
+                        children: vec![],
+                    }],
+                    root: NodeId(0),
+                }
+            }
+
+            pub fn root(&self) -> NodeId {
+                self.root
+            }
+
+            pub fn name(&self, id: NodeId) -> &str {
+                &self.nodes[id.0].name
+            }
+
+            pub fn rename(&mut self, id: NodeId, name: &str) {
+                self.nodes[id.0].name = name.into();
+            }
+
+            pub fn size(&self, id: NodeId) -> Option<u64> {
+                self.nodes[id.0].size
+            }
+
+            pub fn resize(&mut self, id: NodeId, size: Option<u64>) {
+                self.nodes[id.0].size = size;
+            }
+
+            pub fn parent(&self, id: NodeId) -> Option<NodeId> {
+                self.nodes[id.0].parent
+            }
+
+            pub fn children(&self, id: NodeId) -> &[NodeId] {
+                &self.nodes[id.0].children
+            }
+
+            // parent first, then its parent, up to the top of whatever tree
+            // (or detached subtree) `id` is in
+            pub fn ancestors(&self, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+                std::iter::successors(self.parent(id), move |id| self.parent(*id))
+            }
+
+            // the other children of `id`'s parent, in order
+            pub fn siblings(&self, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+                let siblings = match self.parent(id) {
+                    Some(parent) => self.children(parent),
+                    None => &[],
+                };
+                siblings.iter().copied().filter(move |s| *s != id)
+            }
+
+            // where `id` sits among its parent's children
+            pub fn index_of(&self, id: NodeId) -> Option<usize> {
+                let parent = self.parent(id)?;
+                self.children(parent).iter().position(|c| *c == id)
+            }
+
+            // `id` and everything under it, pre-order
+            pub fn descendants(&self, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+                let mut stack = vec![id];
+                std::iter::from_fn(move || {
+                    let id = stack.pop()?;
+                    stack.extend(self.children(id).iter().rev());
+                    Some(id)
+                })
+            }
+
+            pub fn add_child(&mut self, parent: NodeId, name: &str) -> NodeId {
+                let id = NodeId(self.nodes.len());
+                self.nodes.push(Slot {
+                    name: name.into(),
+                    size: None,
+                    parent: Some(parent),
+                    children: vec![],
+                });
+                self.nodes[parent.0].children.push(id);
+                id
+            }
+
+            // copies `node` in as the `index`th child of `parent`
+            pub fn insert(
+                &mut self,
+                parent: NodeId,
+                index: usize,
+                node: &Node,
+            ) -> Result<NodeId, MoveError> {
+                if index > self.children(parent).len() {
+                    return Err(MoveError::Index(index));
+                }
+
+                let id = self.graft(node);
+                self.nodes[id.0].parent = Some(parent);
+                self.nodes[parent.0].children.insert(index, id);
+                Ok(id)
+            }
+
+            // unhooks `id` from its parent. the subtree stays in the arena
+            // and can be reattached anywhere
+            pub fn detach(&mut self, id: NodeId) -> Result<(), MoveError> {
+                let parent = self.parent(id).ok_or(MoveError::Root)?;
+                self.nodes[parent.0].children.retain(|c| *c != id);
+                self.nodes[id.0].parent = None;
+                Ok(())
+            }
+
+            pub fn reattach(
+                &mut self,
+                id: NodeId,
+                parent: NodeId,
+                index: usize,
+            ) -> Result<(), MoveError> {
+                if id == self.root {
+                    return Err(MoveError::Root);
+                }
+                if self.parent(id).is_some() {
+                    return Err(MoveError::Attached(id));
+                }
+                if parent == id || self.ancestors(parent).any(|a| a == id) {
+                    return Err(MoveError::IntoItself);
+                }
+                if index > self.children(parent).len() {
+                    return Err(MoveError::Index(index));
+                }
+
+                self.nodes[id.0].parent = Some(parent);
+                self.nodes[parent.0].children.insert(index, id);
+                Ok(())
+            }
+
+            // an owned copy of the subtree under `id`
+            pub fn to_node(&self, id: NodeId) -> Node {
+                let mut done: Vec<Node> = vec![];
+                let mut stack = vec![(id, false)];
+
+                while let Some((id, expanded)) = stack.pop() {
+                    let slot = &self.nodes[id.0];
+                    if expanded {
+                        let children = done.split_off(done.len() - slot.children.len());
+                        let mut node = Node::new(&slot.name, children);
+                        node.size = slot.size;
+                        done.push(node);
+                    } else {
+                        stack.push((id, true));
+                        stack.extend(slot.children.iter().rev().map(|c| (*c, false)));
+                    }
+                }
+
+                done.pop().expect("the walk ends with `id` itself built")
+            }
+
+            // copies `node` into fresh slots, returning the id of its (parentless) top
+            fn graft(&mut self, node: &Node) -> NodeId {
+                let top = NodeId(self.nodes.len());
+                let mut stack = vec![(node, None)];
+
+                // pre-order, so each parent's children are pushed in order
+                while let Some((node, parent)) = stack.pop() {
+                    let id = NodeId(self.nodes.len());
+                    self.nodes.push(Slot {
+                        name: node.name.clone(),
+                        size: node.size,
+                        parent,
+                        children: Vec::with_capacity(node.children.len()),
+                    });
+                    if let Some(parent) = parent {
+                        self.nodes[parent.0].children.push(id);
+                    }
+                    stack.extend(node.children.iter().rev().map(|c| (c, Some(id))));
+                }
+
+                top
+            }
+        }
+
+        impl From<&Node> for Tree {
+            fn from(node: &Node) -> Self {
+                let mut tree = Tree {
+                    nodes: vec![],
+                    root: NodeId(0),
+                };
+                tree.root = tree.graft(node);
+                tree
+            }
+        }
+
+        impl From<&Tree> for Node {
+            fn from(tree: &Tree) -> Self {
+                tree.to_node(tree.root)
+            }
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+
+            fn sample() -> Tree {
+                Tree::from(&super::super::tests::sample())
+            }
+
+            fn find(tree: &Tree, name: &str) -> NodeId {
+                tree.descendants(tree.root())
+                    .find(|id| tree.name(*id) == name)
+                    .unwrap()
+            }
+
+            fn names(tree: &Tree, ids: impl Iterator<Item = NodeId>) -> Vec<String> {
+                ids.map(|id| tree.name(id).to_string()).collect()
+            }
+
+            #[test]
+            fn round_trip() {
+                let node = Node::new(
+                    "a",
+                    vec![
+                        Node::new("b", vec![Node::new("c", vec![])]),
+                        Node::new("d", vec![]),
+                    ],
+                );
+                assert_eq!(Node::from(&Tree::from(&node)), node);
+            }
+
+            #[test]
+            fn navigation() {
+                let tree = sample();
+                let d = find(&tree, "d");
+
+                assert_eq!(tree.name(tree.parent(d).unwrap()), "b");
+                assert_eq!(names(&tree, tree.ancestors(d)), ["b", "a"]);
+                assert_eq!(names(&tree, tree.siblings(d)), ["c"]);
+                assert_eq!(tree.index_of(d), Some(1));
+                assert_eq!(tree.parent(tree.root()), None);
+                assert_eq!(tree.siblings(tree.root()).count(), 0);
+            }
+
+            #[test]
+            fn move_subtree() {
+                let mut tree = sample();
+                let b = find(&tree, "b");
+                let e = find(&tree, "e");
+
+                tree.detach(b).unwrap();
+                assert_eq!(tree.parent(b), None);
+                assert_eq!(
+                    Node::from(&tree),
+                    Node::new("a", vec![Node::new("e", vec![])])
+                );
+
+                tree.reattach(b, e, 0).unwrap();
+                assert_eq!(
+                    names(&tree, tree.ancestors(find(&tree, "c"))),
+                    ["b", "e", "a"]
+                );
+
+                let x = tree
+                    .insert(b, 1, &Node::new("x", vec![Node::new("y", vec![])]))
+                    .unwrap();
+                tree.rename(x, "z");
+                let expected = Node::new(
+                    "a",
+                    vec![Node::new(
+                        "e",
+                        vec![Node::new(
+                            "b",
+                            vec![
+                                Node::new("c", vec![]),
+                                Node::new("z", vec![Node::new("y", vec![])]),
+                                Node::new("d", vec![]),
+                            ],
+                        )],
+                    )],
+                );
+                assert_eq!(Node::from(&tree), expected);
+            }
+
+            #[test]
+            fn bad_moves() {
+                let mut tree = sample();
+                let root = tree.root();
+                let b = find(&tree, "b");
+                let c = find(&tree, "c");
+
+                assert_eq!(tree.detach(root), Err(MoveError::Root));
+                assert_eq!(tree.reattach(b, root, 0), Err(MoveError::Attached(b)));
+
+                tree.detach(b).unwrap();
+                assert_eq!(tree.reattach(b, c, 0), Err(MoveError::IntoItself));
+                assert_eq!(tree.reattach(b, b, 0), Err(MoveError::IntoItself));
+                assert_eq!(tree.reattach(b, root, 5), Err(MoveError::Index(5)));
+                assert_eq!(tree.reattach(b, root, 1), Ok(()));
+            }
+        }
+    }
+
+    mod diff {
+        use super::{
+            arena::{MoveError, NodeId, Tree},
+            merkle::Digests,
+            Node,
+        };
+        use serde::{Deserialize, Serialize};
+        use std::{
+            collections::{HashMap, HashSet},
+            fmt,
+            hash::Hash,
+        };
+
+        // one step of an edit script. paths are child indices from the root
+        // (the root itself is `[]`) and are read against the tree as it is
+        // when the step runs, after every step before it
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+        pub enum Op {
+            Insert {
+                at: Vec<usize>,
+                node: Node,
+            },
+            Delete {
+                at: Vec<usize>,
+            },
+            // `from` is checked against the name found at `at`
+            Rename {
+                at: Vec<usize>,
+                from: String,
+                to: String,
+            },
+            // `to` is read once the subtree has been taken out of `from`
+            Move {
+                from: Vec<usize>,
+                to: Vec<usize>,
+            },
+            // Node::size, checked against `from` like a rename
+            Resize {
+                at: Vec<usize>,
+                from: Option<u64>,
+                to: Option<u64>,
+            },
+        }
+
+        #[derive(Debug, PartialEq)]
+        pub enum PatchError {
+            // nothing at this path, or no room for a new child there
+            Path(Vec<usize>),
+            // a rename or resize whose `from` isn't what's at the path
+            Conflict {
+                at: Vec<usize>,
+                expected: String,
+                found: String,
+            },
+            Move(MoveError),
+        }
+
+        impl fmt::Display for PatchError {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                match self {
+                    PatchError::Path(at) => write!(f, "no node at {:?}", at),
+                    PatchError::Conflict {
+                        at,
+                        expected,
+                        found,
+                    } => write!(f, "expected {} at {:?}, found {}", expected, at, found),
+                    PatchError::Move(e) => write!(f, "{}", e),
+                }
+            }
+        }
+
+        impl std::error::Error for PatchError {}
+
+        impl From<MoveError> for PatchError {
+            fn from(e: MoveError) -> Self {
+                PatchError::Move(e)
+            }
+        }
+
+        fn child_names(node: &Node) -> impl Iterator<Item = &str> {
+            node.children.iter().map(|c| c.name.as_str())
+        }
+
+        // index pairs of a common subsequence of `a` and `b`, in order. the
+        // common head and tail are taken as they are; in between, items that
+        // occur once on each side are lined up first (a longest increasing
+        // run of them) and only the gaps between those are searched in full.
+        // sibling names are mostly unique, so a shuffled directory is
+        // n log n, and nothing here needs more than linear space
+        fn lcs<T: Hash + Eq>(a: &[T], b: &[T]) -> Vec<(usize, usize)> {
+            let head = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+            let tail = a[head..]
+                .iter()
+                .rev()
+                .zip(b[head..].iter().rev())
+                .take_while(|(x, y)| x == y)
+                .count();
+            let (a_mid, b_mid) = (&a[head..a.len() - tail], &b[head..b.len() - tail]);
+
+            // (count in a, count in b, last index in a, last index in b)
+            let mut seen: HashMap<&T, (usize, usize, usize, usize)> = HashMap::new();
+            for (i, x) in a_mid.iter().enumerate() {
+                let entry = seen.entry(x).or_default();
+                entry.0 += 1;
+                entry.2 = i;
+            }
+            for (j, y) in b_mid.iter().enumerate() {
+                if let Some(entry) = seen.get_mut(y) {
+                    entry.1 += 1;
+                    entry.3 = j;
+                }
+            }
+            let mut unique: Vec<(usize, usize)> = seen
+                .values()
+                .filter(|(in_a, in_b, ..)| (*in_a, *in_b) == (1, 1))
+                .map(|(_, _, i, j)| (*i, *j))
+                .collect();
+            unique.sort_unstable();
+
+            let mut pairs: Vec<_> = (0..head).map(|i| (i, i)).collect();
+            // items on one side only can't be part of it, so they never reach
+            // the quadratic search. with nothing in common that is all of them
+            let shared = |x: &T| seen.get(x).is_some_and(|(_, in_b, ..)| *in_b > 0);
+            let (mut i, mut j) = (0, 0);
+            for (x, y) in increasing(&unique)
+                .into_iter()
+                .chain([(a_mid.len(), b_mid.len())])
+            {
+                let ka: Vec<usize> = (i..x).filter(|k| shared(&a_mid[*k])).collect();
+                let kb: Vec<usize> = (j..y).filter(|k| shared(&b_mid[*k])).collect();
+                if !ka.is_empty() && !kb.is_empty() {
+                    let xa: Vec<&T> = ka.iter().map(|k| &a_mid[*k]).collect();
+                    let xb: Vec<&T> = kb.iter().map(|k| &b_mid[*k]).collect();
+                    let mut found = vec![];
+                    hirschberg(&xa, &xb, 0, 0, &mut found);
+                    pairs.extend(found.into_iter().map(|(p, q)| (head + ka[p], head + kb[q])));
+                }
+                if x < a_mid.len() {
+                    pairs.push((head + x, head + y));
+                }
+                (i, j) = (x + 1, y + 1);
+            }
+            pairs.extend((0..tail).map(|k| (a.len() - tail + k, b.len() - tail + k)));
+            pairs
+        }
+
+        // the longest run of `pairs` (sorted by the first index) whose second
+        // indices go up too, patience style
+        fn increasing(pairs: &[(usize, usize)]) -> Vec<(usize, usize)> {
+            // tops[k]: the pair ending the best run of length k + 1 so far
+            let mut tops: Vec<usize> = vec![];
+            let mut prev: Vec<Option<usize>> = vec![None; pairs.len()];
+            for (p, (_, y)) in pairs.iter().enumerate() {
+                let k = tops.partition_point(|t| pairs[*t].1 < *y);
+                prev[p] = k.checked_sub(1).map(|k| tops[k]);
+                if k == tops.len() {
+                    tops.push(p);
+                } else {
+                    tops[k] = p;
+                }
+            }
+            let mut run: Vec<_> = std::iter::successors(tops.last().copied(), |p| prev[*p])
+                .map(|p| pairs[p])
+                .collect();
+            run.reverse();
+            run
+        }
+
+        // a longest common subsequence in linear space: split `a` in half,
+        // find where `b` splits by meeting lcs lengths from both ends, and
+        // solve the two halves. recursion is log |a| deep
+        fn hirschberg<T: Eq>(
+            a: &[T],
+            b: &[T],
+            i: usize,
+            j: usize,
+            pairs: &mut Vec<(usize, usize)>,
+        ) {
+            if a.is_empty() || b.is_empty() {
+                return;
+            }
+            if let [x] = a {
+                if let Some(k) = b.iter().position(|y| y == x) {
+                    pairs.push((i, j + k));
+                }
+                return;
+            }
+            let mid = a.len() / 2;
+            let front = lcs_lengths(a[..mid].iter(), b.iter());
+            let back = lcs_lengths(a[mid..].iter().rev(), b.iter().rev());
+            let split = (0..=b.len())
+                .max_by_key(|k| (front[*k] + back[b.len() - k], std::cmp::Reverse(*k)))
+                .expect("0..=len is never empty");
+            hirschberg(&a[..mid], &b[..split], i, j, pairs);
+            hirschberg(&a[mid..], &b[split..], i + mid, j + split, pairs);
+        }
+
+        // lengths[k]: lcs length of all of `a` and the first k items of `b`
+        fn lcs_lengths<'t, T: Eq + 't>(
+            a: impl Iterator<Item = &'t T>,
+            b: impl Iterator<Item = &'t T> + Clone,
+        ) -> Vec<usize> {
+            let mut row = vec![0; b.clone().count() + 1];
+            for x in a {
+                let mut diagonal = 0;
+                for (k, y) in b.clone().enumerate() {
+                    let above = row[k + 1];
+                    row[k + 1] = if x == y {
+                        diagonal + 1
+                    } else {
+                        above.max(row[k])
+                    };
+                    diagonal = above;
+                }
+            }
+            row
+        }
+
+        // which node of `a` each node of `b` came from, if any
+        struct Matching<'a> {
+            a: Digests<'a>,
+            b: Digests<'a>,
+            from: Vec<Option<usize>>,
+            used: Vec<bool>,
+        }
+
+        impl<'a> Matching<'a> {
+            fn new(a: &'a Node, b: &'a Node) -> Self {
+                let (a, b) = (Digests::new(a), Digests::new(b));
+                let mut matching = Matching {
+                    from: vec![None; b.nodes.len()],
+                    used: vec![false; a.nodes.len()],
+                    a,
+                    b,
+                };
+                // the roots always correspond, renamed or not
+                matching.link(0, 0);
+
+                // same names in the same order under nodes that already match.
+                // subtrees that didn't change are matched in one go and not
+                // looked at again
+                let mut j = 0;
+                while j < matching.b.nodes.len() {
+                    if let Some(i) = matching.from[j] {
+                        if matching.unchanged(i, j) {
+                            j += matching.b.size[j];
+                            continue;
+                        }
+                        matching.children(i, j, false);
+                    }
+                    j += 1;
+                }
+                matching.moves();
+                // then leftovers side by side under the same parent
+                for j in 0..matching.b.nodes.len() {
+                    if let Some(i) = matching.from[j] {
+                        matching.children(i, j, true);
+                    }
+                }
+                matching
+            }
+
+            fn link(&mut self, i: usize, j: usize) {
+                self.from[j] = Some(i);
+                self.used[i] = true;
+            }
+
+            // links everything under `j` to the same place under `i` if the
+            // two subtrees have the same shape and nothing in them is matched
+            // yet. sizes may differ, those come out as resizes
+            fn unchanged(&mut self, i: usize, j: usize) -> bool {
+                let size = self.b.size[j];
+                let same = self.a.same(i, &self.b, j)
+                    && self.used[i + 1..i + size].iter().all(|u| !u)
+                    && self.from[j + 1..j + size].iter().all(|f| f.is_none());
+                if same {
+                    for k in 1..size {
+                        self.link(i + k, j + k);
+                    }
+                }
+                same
+            }
+
+            // pairs up the unmatched children of `i` and `j` by name. with
+            // `renames`, children left between two pairs are paired too when
+            // they sit at the same offset and have the same child names
+            fn children(&mut self, i: usize, j: usize, renames: bool) {
+                let ca: Vec<usize> = self.a.children(i).filter(|k| !self.used[*k]).collect();
+                let cb: Vec<usize> = self
+                    .b
+                    .children(j)
+                    .filter(|k| self.from[*k].is_none())
+                    .collect();
+                let names = |tree: &Digests, ids: &[usize]| -> Vec<String> {
+                    ids.iter().map(|k| tree.nodes[*k].name.clone()).collect()
+                };
+                let pairs = lcs(&names(&self.a, &ca), &names(&self.b, &cb));
+
+                for (x, y) in &pairs {
+                    self.link(ca[*x], cb[*y]);
+                }
+                if !renames {
+                    return;
+                }
+
+                let mut prev = (0, 0);
+                for (x, y) in pairs.into_iter().chain([(ca.len(), cb.len())]) {
+                    for (p, q) in (prev.0..x).zip(prev.1..y) {
+                        if child_names(self.a.nodes[ca[p]]).eq(child_names(self.b.nodes[cb[q]])) {
+                            self.link(ca[p], cb[q]);
+                        }
+                    }
+                    prev = (x + 1, y + 1);
+                }
+            }
+
+            // an unmatched subtree of b with the same shape as an unmatched
+            // subtree of a was moved there
+            fn moves(&mut self) {
+                let mut by_hash: HashMap<u64, Vec<usize>> = HashMap::new();
+                for (i, hash) in self.a.hashes.iter().enumerate() {
+                    if !self.used[i] {
+                        by_hash.entry(*hash).or_default().push(i);
+                    }
+                }
+
+                let mut j = 0;
+                while j < self.b.nodes.len() {
+                    if self.from[j].is_some() {
+                        j += 1;
+                        continue;
+                    }
+
+                    let size = self.b.size[j];
+                    let found = by_hash.get(&self.b.hashes[j]).and_then(|candidates| {
+                        candidates.iter().copied().find(|i| {
+                            self.a.size[*i] == size
+                                && self.used[*i..*i + size].iter().all(|u| !u)
+                                && self.a.same(*i, &self.b, j)
+                        })
+                    });
+
+                    match found {
+                        Some(i) => {
+                            for k in 0..size {
+                                self.link(i + k, j + k);
+                            }
+                            j += size;
+                        }
+                        None => j += 1,
+                    }
+                }
+            }
+        }
+
+        // which slots of a sibling list are taken, as a Fenwick tree, so a
+        // node's index among its siblings is a count of the taken slots ahead
+        // of it in log time
+        #[derive(Default)]
+        struct Slots {
+            counts: Vec<usize>,
+            taken: Vec<bool>,
+        }
+
+        impl Slots {
+            fn new(taken: Vec<bool>) -> Self {
+                let mut slots = Slots {
+                    counts: vec![0; taken.len()],
+                    taken: vec![false; taken.len()],
+                };
+                for (s, _) in taken.iter().enumerate().filter(|(_, t)| **t) {
+                    slots.take(s);
+                }
+                slots
+            }
+
+            fn update(&mut self, slot: usize, taken: bool) {
+                self.taken[slot] = taken;
+                let mut k = slot + 1;
+                while k <= self.counts.len() {
+                    if taken {
+                        self.counts[k - 1] += 1;
+                    } else {
+                        self.counts[k - 1] -= 1;
+                    }
+                    k += k & k.wrapping_neg();
+                }
+            }
+
+            fn take(&mut self, slot: usize) {
+                self.update(slot, true);
+            }
+
+            fn free(&mut self, slot: usize) {
+                self.update(slot, false);
+            }
+
+            // taken slots before `slot`
+            fn before(&self, slot: usize) -> usize {
+                let (mut k, mut n) = (slot, 0);
+                while k > 0 {
+                    n += self.counts[k - 1];
+                    k -= k & k.wrapping_neg();
+                }
+                n
+            }
+        }
+
+        // the tree as the ops so far have left it. vertices are a's pre-order
+        // indices, then b's shifted past them for the nodes the patch inserts.
+        // every parent lays its children out in slots fixed up front, so a
+        // move is freeing one slot and taking another rather than shifting
+        // the whole sibling list
+        struct Layout {
+            // parent and slot, for every attached vertex but the root
+            at: Vec<Option<(usize, usize)>>,
+            // the vertex in each of a parent's slots
+            lists: Vec<Vec<usize>>,
+            slots: Vec<Slots>,
+        }
+
+        impl Layout {
+            fn new(a: &Digests, b: &Digests) -> Self {
+                let vertices = a.nodes.len() + b.nodes.len();
+                let mut layout = Layout {
+                    at: vec![None; vertices],
+                    lists: vec![vec![]; vertices],
+                    slots: (0..vertices).map(|_| Slots::default()).collect(),
+                };
+                for i in 0..a.nodes.len() {
+                    let children: Vec<usize> = a.children(i).collect();
+                    layout.relayout(i, children.clone(), vec![true; children.len()]);
+                }
+                layout
+            }
+
+            // replaces `parent`'s slots. the ones marked taken hold children
+            // already there, the rest are for children placed later
+            fn relayout(&mut self, parent: usize, list: Vec<usize>, taken: Vec<bool>) {
+                for (s, v) in list.iter().enumerate().filter(|(s, _)| taken[*s]) {
+                    self.at[*v] = Some((parent, s));
+                }
+                self.slots[parent] = Slots::new(taken);
+                self.lists[parent] = list;
+            }
+
+            // the children of `parent` now, in order
+            fn children(&self, parent: usize) -> Vec<usize> {
+                let taken = &self.slots[parent].taken;
+                let list = &self.lists[parent];
+                (0..list.len())
+                    .filter(|s| taken[*s])
+                    .map(|s| list[s])
+                    .collect()
+            }
+
+            fn place(&mut self, v: usize, parent: usize, slot: usize) {
+                self.at[v] = Some((parent, slot));
+                self.slots[parent].take(slot);
+            }
+
+            fn remove(&mut self, v: usize) {
+                if let Some((parent, slot)) = self.at[v].take() {
+                    self.slots[parent].free(slot);
+                }
+            }
+
+            // where `v` is, as child indices from the root
+            fn path(&self, mut v: usize) -> Vec<usize> {
+                let mut path = vec![];
+                while let Some((parent, slot)) = self.at[v] {
+                    path.push(self.slots[parent].before(slot));
+                    v = parent;
+                }
+                path.reverse();
+                path
+            }
+        }
+
+        fn resolve(tree: &Tree, path: &[usize]) -> Option<NodeId> {
+            path.iter()
+                .try_fold(tree.root(), |id, i| tree.children(id).get(*i).copied())
+        }
+
+        // `id` as a slash separated list of names, root first
+        fn name_path(tree: &Tree, id: NodeId) -> String {
+            let mut names: Vec<&str> = std::iter::once(id)
+                .chain(tree.ancestors(id))
+                .map(|id| tree.name(id))
+                .collect();
+            names.reverse();
+            names.join("/")
+        }
+
+        // the edit script that turns `a` into `b`. nodes are matched by name
+        // under parents that match, exact copies elsewhere in the tree count
+        // as moves, and what's left pairs up as renames where the children
+        // line up. everything else is deleted or inserted. deletes go last,
+        // so nodes can first be moved out of subtrees that are going away
+        pub fn diff(a: &Node, b: &Node) -> Vec<Op> {
+            let matching = Matching::new(a, b);
+            let (fa, fb) = (&matching.a, &matching.b);
+            let mut layout = Layout::new(fa, fb);
+            let mut ops = vec![];
+
+            // b nodes with something matched at or under them
+            let mut keeps = vec![false; fb.nodes.len()];
+            for j in (0..fb.nodes.len()).rev() {
+                keeps[j] = matching.from[j].is_some() || fb.children(j).any(|k| keeps[k]);
+            }
+
+            // the vertex each b node ended up as
+            let mut placed: Vec<Option<usize>> = vec![None; fb.nodes.len()];
+            placed[0] = Some(0);
+            if a.name != b.name {
+                ops.push(Op::Rename {
+                    at: vec![],
+                    from: a.name.clone(),
+                    to: b.name.clone(),
+                });
+            }
+            if a.size != b.size {
+                ops.push(Op::Resize {
+                    at: vec![],
+                    from: a.size,
+                    to: b.size,
+                });
+            }
+
+            // top down. under each placed node, the children that are already
+            // there in the right order stay put; every other child of b is
+            // moved or inserted right after the one before it. whatever else
+            // is still there gets skipped over
+            let mut stack = vec![0];
+            while let Some(j) = stack.pop() {
+                let parent = placed[j].expect("parents are placed before their children");
+                let kids: Vec<usize> = fb.children(j).collect();
+                let vertex = |k: usize| matching.from[k].unwrap_or(fa.nodes.len() + k);
+
+                // which of b's children are here already, in the order they are
+                let here_now = layout.children(parent);
+                let target: HashMap<usize, usize> = kids
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(index, k)| Some((matching.from[*k]?, index)))
+                    .collect();
+                let here: Vec<usize> = here_now
+                    .iter()
+                    .filter_map(|v| target.get(v).copied())
+                    .collect();
+                let mut sorted = here.clone();
+                sorted.sort_unstable();
+                let mut stays = vec![false; kids.len()];
+                for (x, _) in lcs(&here, &sorted) {
+                    stays[here[x]] = true;
+                }
+
+                // lay the slots out as they will end up: what's here now, and
+                // a fresh slot right after the previous child for each one
+                // that comes or moves in. a child moving along this list has
+                // both, the old one freed when it goes
+                let mut list = here_now.clone();
+                let mut next: Vec<Option<usize>> = (1..=list.len())
+                    .map(|s| (s < list.len()).then_some(s))
+                    .collect();
+                let mut first = (!list.is_empty()).then_some(0);
+                let slot_of: HashMap<usize, usize> =
+                    here_now.iter().enumerate().map(|(s, v)| (*v, s)).collect();
+                let mut fresh = vec![None; kids.len()];
+                let mut prev: Option<usize> = None;
+                for (index, k) in kids.iter().copied().enumerate() {
+                    let slot = if stays[index] {
+                        slot_of[&vertex(k)]
+                    } else {
+                        let slot = list.len();
+                        list.push(vertex(k));
+                        let after = match prev {
+                            Some(p) => &mut next[p],
+                            None => &mut first,
+                        };
+                        let then = after.replace(slot);
+                        next.push(then);
+                        fresh[index] = Some(slot);
+                        slot
+                    };
+                    prev = Some(slot);
+                }
+                let order: Vec<usize> = std::iter::successors(first, |s| next[*s]).collect();
+                let mut rank = vec![0; list.len()];
+                for (r, s) in order.iter().enumerate() {
+                    rank[*s] = r;
+                }
+                let taken = order.iter().map(|s| *s < here_now.len()).collect();
+                layout.relayout(parent, order.iter().map(|s| list[*s]).collect(), taken);
+
+                for (index, k) in kids.iter().copied().enumerate() {
+                    let node = fb.nodes[k];
+                    let v = vertex(k);
+                    match matching.from[k] {
+                        Some(i) => {
+                            if let Some(slot) = fresh[index] {
+                                let from = layout.path(v);
+                                layout.remove(v);
+                                layout.place(v, parent, rank[slot]);
+                                // the ones that moved around it can leave it
+                                // where it was
+                                let to = layout.path(v);
+                                if from != to {
+                                    ops.push(Op::Move { from, to });
+                                }
+                            }
+                            if fa.nodes[i].name != node.name {
+                                ops.push(Op::Rename {
+                                    at: layout.path(v),
+                                    from: fa.nodes[i].name.clone(),
+                                    to: node.name.clone(),
+                                });
+                            }
+                            if fa.nodes[i].size != node.size {
+                                ops.push(Op::Resize {
+                                    at: layout.path(v),
+                                    from: fa.nodes[i].size,
+                                    to: node.size,
+                                });
+                            }
+                        }
+                        None => {
+                            // brand new all the way down goes in as one
+                            // insert, otherwise an empty node to move into
+                            let node = if keeps[k] {
+                                let mut empty = Node::new(&node.name, vec![]);
+                                empty.size = node.size;
+                                empty
+                            } else {
+                                node.clone()
+                            };
+                            let slot = fresh[index].expect("new children never stay");
+                            layout.place(v, parent, rank[slot]);
+                            ops.push(Op::Insert {
+                                at: layout.path(v),
+                                node,
+                            });
+                        }
+                    }
+                    placed[k] = Some(v);
+                }
+                stack.extend(kids.iter().rev().filter(|k| keeps[**k]));
+            }
+
+            // the rest goes, last first so the paths of the others hold.
+            // subtrees inserted whole have nothing to lose
+            let kept: HashSet<usize> = placed.iter().flatten().copied().collect();
+            let walked = placed.iter().zip(&keeps).filter(|(_, keep)| **keep);
+            for v in walked.filter_map(|(v, _)| *v) {
+                let extra: Vec<usize> = layout
+                    .children(v)
+                    .into_iter()
+                    .filter(|c| !kept.contains(c))
+                    .collect();
+                for extra in extra.into_iter().rev() {
+                    ops.push(Op::Delete {
+                        at: layout.path(extra),
+                    });
+                    layout.remove(extra);
+                }
+            }
+
+            ops
+        }
+
+        fn apply(tree: &mut Tree, op: &Op) -> Result<(), PatchError> {
+            let parent_of = |tree: &Tree, at: &[usize]| {
+                let (index, parent) = at.split_last().ok_or(PatchError::Path(at.to_vec()))?;
+                let parent = resolve(tree, parent).ok_or(PatchError::Path(at.to_vec()))?;
+                Ok::<_, PatchError>((parent, *index))
+            };
+            let node_at =
+                |tree: &Tree, at: &[usize]| resolve(tree, at).ok_or(PatchError::Path(at.to_vec()));
+
+            match op {
+                Op::Insert { at, node } => {
+                    let (parent, index) = parent_of(tree, at)?;
+                    tree.insert(parent, index, node)
+                        .map_err(|_| PatchError::Path(at.clone()))?;
+                }
+                Op::Delete { at } => tree.detach(node_at(tree, at)?)?,
+                Op::Rename { at, from, to } => {
+                    let id = node_at(tree, at)?;
+                    if tree.name(id) != from {
+                        return Err(PatchError::Conflict {
+                            at: at.clone(),
+                            expected: from.clone(),
+                            found: tree.name(id).to_string(),
+                        });
+                    }
+                    tree.rename(id, to);
+                }
+                Op::Resize { at, from, to } => {
+                    let id = node_at(tree, at)?;
+                    if tree.size(id) != *from {
+                        return Err(PatchError::Conflict {
+                            at: at.clone(),
+                            expected: format!("{:?}", from),
+                            found: format!("{:?}", tree.size(id)),
+                        });
+                    }
+                    tree.resize(id, *to);
+                }
+                Op::Move { from, to } => {
+                    let id = node_at(tree, from)?;
+                    tree.detach(id)?;
+                    let (parent, index) = parent_of(tree, to)?;
+                    tree.reattach(id, parent, index).map_err(|e| match e {
+                        MoveError::Index(_) => PatchError::Path(to.clone()),
+                        e => PatchError::Move(e),
+                    })?;
+                }
+            }
+            Ok(())
+        }
+
+        // runs the script over a copy of `node`; `node` is untouched on error
+        pub fn apply_patch(node: &Node, patch: &[Op]) -> Result<Node, PatchError> {
+            let mut tree = Tree::from(node);
+            for op in patch {
+                apply(&mut tree, op)?;
+            }
+            Ok(Node::from(&tree))
+        }
+
+        // the script as it plays out over `node`, one line per node touched:
+        //
+        //   --- a
+        //   +++ b
+        //   - a/gone
+        //   + a/new
+        //   ~ a/old -> a/renamed
+        //   > a/x/moved -> a/moved @0
+        //   # a/file 10 -> 12
+        //
+        // deleted and inserted subtrees list every node in them, moves say
+        // which child they end up as
+        pub fn render(node: &Node, patch: &[Op]) -> Result<String, PatchError> {
+            let mut tree = Tree::from(node);
+            let mut lines = vec![];
+
+            for op in patch {
+                match op {
+                    Op::Delete { at } => {
+                        let id = resolve(&tree, at).ok_or(PatchError::Path(at.clone()))?;
+                        for id in tree.descendants(id) {
+                            lines.push(format!("- {}", name_path(&tree, id)));
+                        }
+                        apply(&mut tree, op)?;
+                    }
+                    Op::Insert { at, .. } => {
+                        apply(&mut tree, op)?;
+                        let id = resolve(&tree, at).ok_or(PatchError::Path(at.clone()))?;
+                        for id in tree.descendants(id) {
+                            lines.push(format!("+ {}", name_path(&tree, id)));
+                        }
+                    }
+                    Op::Rename { at, .. } => {
+                        let id = resolve(&tree, at).ok_or(PatchError::Path(at.clone()))?;
+                        let before = name_path(&tree, id);
+                        apply(&mut tree, op)?;
+                        lines.push(format!("~ {} -> {}", before, name_path(&tree, id)));
+                    }
+                    Op::Resize { at, from, to } => {
+                        let id = resolve(&tree, at).ok_or(PatchError::Path(at.clone()))?;
+                        apply(&mut tree, op)?;
+                        let size = |size: &Option<u64>| size.map_or("-".into(), |s| s.to_string());
+                        lines.push(format!(
+                            "# {} {} -> {}",
+                            name_path(&tree, id),
+                            size(from),
+                            size(to)
+                        ));
+                    }
+                    Op::Move { from, .. } => {
+                        let id = resolve(&tree, from).ok_or(PatchError::Path(from.clone()))?;
+                        let (before, was) = (name_path(&tree, id), tree.index_of(id));
+                        apply(&mut tree, op)?;
+                        let index = tree.index_of(id).unwrap_or_default();
+                        let after = name_path(&tree, id);
+                        // a reorder under the same parent only shows in the index
+                        lines.push(if before == after {
+                            format!("> {} @{} -> @{}", before, was.unwrap_or_default(), index)
+                        } else {
+                            format!("> {} -> {} @{}", before, after, index)
+                        });
+                    }
+                }
+            }
+
+            let header = format!("--- {}\n+++ {}\n", node.name, tree.name(tree.root()));
+            Ok(header + &lines.iter().map(|l| format!("{}\n", l)).collect::<String>())
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+            use crate::stateful::tests::leaf;
+
+            fn round_trip(a: &Node, b: &Node) -> Vec<Op> {
+                let patch = diff(a, b);
+                assert_eq!(&apply_patch(a, &patch).unwrap(), b, "patch: {:#?}", patch);
+                patch
+            }
+
+            fn before() -> Node {
+                Node::new(
+                    "root",
+                    vec![
+                        Node::new("src", vec![leaf("main.rs"), leaf("lib.rs")]),
+                        Node::new("old", vec![leaf("util.rs")]),
+                        leaf("README.md"),
+                    ],
+                )
+            }
+
+            fn after() -> Node {
+                Node::new(
+                    "root",
+                    vec![
+                        Node::new("src", vec![leaf("lib.rs"), leaf("app.rs"), leaf("util.rs")]),
+                        Node::new("docs", vec![leaf("index.md")]),
+                        leaf("README"),
+                    ],
+                )
+            }
+
+            #[test]
+            fn identical_trees() {
+                assert_eq!(round_trip(&before(), &before()), vec![]);
+            }
+
+            #[test]
+            fn every_kind_of_op() {
+                let patch = round_trip(&before(), &after());
+                let expected = "\
+--- root
++++ root
++ root/docs
++ root/docs/index.md
+~ root/README.md -> root/README
+> root/src/main.rs @0 -> @1
+~ root/src/main.rs -> root/src/app.rs
+> root/old/util.rs -> root/src/util.rs @2
+- root/old
+";
+                assert_eq!(render(&before(), &patch).unwrap(), expected);
+            }
+
+            #[test]
+            fn lcs_lengths() {
+                let pairs = lcs(b"ABCBDAB", b"BDCABA");
+                assert_eq!(pairs.len(), 4);
+                assert!(pairs.windows(2).all(|w| w[0].0 < w[1].0 && w[0].1 < w[1].1));
+                assert!(pairs.iter().all(|(i, j)| b"ABCBDAB"[*i] == b"BDCABA"[*j]));
+
+                // a table for this would be 10^10 entries
+                let a: Vec<usize> = (0..100_000).collect();
+                let mut b = a.clone();
+                b.rotate_left(1);
+                assert_eq!(lcs(&a, &b).len(), 99_999);
+            }
+
+            // one wide directory: nothing in common, then everything reversed.
+            // both stay well clear of n * m work
+            #[test]
+            fn wide_directories() {
+                let dir =
+                    |names: Vec<String>| Node::new("r", names.iter().map(|n| leaf(n)).collect());
+                let a = dir((0..20_000).map(|i| format!("a{}", i)).collect());
+                let b = dir((0..20_000).map(|i| format!("b{}", i)).collect());
+                let patch = diff(&a, &b);
+                assert_eq!(patch.len(), 20_000);
+                assert!(patch.iter().all(|op| matches!(op, Op::Rename { .. })));
+
+                let reversed = dir((0..20_000).rev().map(|i| format!("a{}", i)).collect());
+                let patch = diff(&a, &reversed);
+                assert_eq!(patch.len(), 19_999);
+                assert!(patch.iter().all(|op| matches!(op, Op::Move { .. })));
+            }
+
+            #[test]
+            fn deep_chains() {
+                let a = crate::stateful::tests::chain(100_000);
+                assert_eq!(diff(&a, &a), vec![]);
+
+                let mut b = crate::stateful::tests::chain(100_000);
+                let mut node = &mut b;
+                while !node.children.is_empty() {
+                    node = &mut node.children[0];
+                }
+                node.name = "end".to_string();
+                let patch = diff(&a, &b);
+                assert!(matches!(patch.as_slice(), [Op::Rename { to, .. }] if to == "end"));
+            }
+
+            #[test]
+            fn moves_out_of_deleted_subtree() {
+                let a = Node::new(
+                    "r",
+                    vec![Node::new("x", vec![Node::new("deep", vec![leaf("keep")])])],
+                );
+                let b = Node::new("s", vec![Node::new("deep", vec![leaf("keep")]), leaf("y")]);
+                let patch = round_trip(&a, &b);
+                assert!(patch.iter().any(|op| matches!(op, Op::Move { .. })));
+            }
+
+            #[test]
+            fn round_trips() {
+                let cases = [
+                    (leaf("a"), leaf("b")),
+                    (leaf("a"), Node::new("a", vec![leaf("b"), leaf("c")])),
+                    (Node::new("a", vec![leaf("b"), leaf("c")]), leaf("a")),
+                    (
+                        Node::new("a", vec![leaf("b"), leaf("c"), leaf("d")]),
+                        Node::new("a", vec![leaf("d"), leaf("c"), leaf("b")]),
+                    ),
+                    (
+                        Node::new(
+                            "a",
+                            vec![leaf("x"), leaf("x"), Node::new("x", vec![leaf("x")])],
+                        ),
+                        Node::new(
+                            "a",
+                            vec![Node::new("x", vec![leaf("x"), leaf("x")]), leaf("x")],
+                        ),
+                    ),
+                    (
+                        Node::new(
+                            "a",
+                            vec![Node::new("b", vec![Node::new("c", vec![leaf("d")])])],
+                        ),
+                        Node::new("a", vec![leaf("d"), Node::new("c", vec![leaf("b")])]),
+                    ),
+                ];
+
+                for (a, b) in &cases {
+                    round_trip(a, b);
+                    round_trip(b, a);
+                }
+            }
+
+            #[test]
+            fn sizes_round_trip() {
+                // what two Node::from_dir snapshots of the same files look like
+                let sized = |name: &str, size: Option<u64>| {
+                    let mut node = leaf(name);
+                    node.size = size;
+                    node
+                };
+                let a = Node::new(
+                    "root",
+                    vec![
+                        Node::new(
+                            "src",
+                            vec![sized("main.rs", Some(10)), sized("lib.rs", Some(3))],
+                        ),
+                        sized("README", Some(5)),
+                    ],
+                );
+                let b = Node::new(
+                    "root",
+                    vec![
+                        Node::new(
+                            "src",
+                            vec![sized("main.rs", Some(12)), sized("lib.rs", Some(3))],
+                        ),
+                        sized("README", None),
+                        sized("new.rs", Some(1)),
+                    ],
+                );
+
+                let patch = round_trip(&a, &b);
+                round_trip(&b, &a);
+                let expected = "\
+--- root
++++ root
+# root/README 5 -> -
++ root/new.rs
+# root/src/main.rs 10 -> 12
+";
+                assert_eq!(render(&a, &patch).unwrap(), expected);
+
+                let stale = Op::Resize {
+                    at: vec![1],
+                    from: Some(4),
+                    to: None,
+                };
+                assert_eq!(
+                    apply_patch(&a, &[stale]),
+                    Err(PatchError::Conflict {
+                        at: vec![1],
+                        expected: "Some(4)".into(),
+                        found: "Some(5)".into(),
+                    })
+                );
+            }
+
+            // small trees over a handful of names, so the same name turns
+            // up all over and in different places on each side
+            #[test]
+            fn round_trips_generated() {
+                let mut seed = 7u64;
+                let mut next = move |n: u64| {
+                    seed = seed
+                        .wrapping_mul(6364136223846793005)
+                        .wrapping_add(1442695040888963407);
+                    (seed >> 33) % n
+                };
+                let mut grow = |depth: usize| {
+                    let mut nodes: Vec<Node> = (0..12)
+                        .map(|_| {
+                            let mut node = leaf(&next(5).to_string());
+                            node.size = Some(next(3));
+                            node
+                        })
+                        .collect();
+                    for _ in 0..depth * 4 {
+                        let at = next(nodes.len() as u64) as usize;
+                        let child = nodes.remove(at);
+                        let parent = next(nodes.len() as u64) as usize;
+                        nodes[parent].children.push(child);
+                    }
+                    Node::new("r", nodes)
+                };
+
+                for _ in 0..200 {
+                    let (a, b) = (grow(2), grow(2));
+                    round_trip(&a, &b);
+                }
+            }
+
+            #[test]
+            fn patch_errors() {
+                let a = before();
+
+                assert_eq!(
+                    apply_patch(&a, &[Op::Delete { at: vec![] }]),
+                    Err(PatchError::Move(MoveError::Root))
+                );
+                assert_eq!(
+                    apply_patch(&a, &[Op::Delete { at: vec![0, 7] }]),
+                    Err(PatchError::Path(vec![0, 7]))
+                );
+                assert_eq!(
+                    apply_patch(
+                        &a,
+                        &[Op::Rename {
+                            at: vec![2],
+                            from: "LICENSE".into(),
+                            to: "x".into(),
+                        }]
+                    ),
+                    Err(PatchError::Conflict {
+                        at: vec![2],
+                        expected: "LICENSE".into(),
+                        found: "README.md".into(),
+                    })
+                );
+                assert_eq!(
+                    apply_patch(
+                        &a,
+                        &[Op::Insert {
+                            at: vec![9],
+                            node: leaf("x")
+                        }]
+                    ),
+                    Err(PatchError::Path(vec![9]))
+                );
+                assert_eq!(
+                    apply_patch(
+                        &a,
+                        &[Op::Move {
+                            from: vec![0],
+                            to: vec![5, 0]
+                        }]
+                    ),
+                    Err(PatchError::Path(vec![5, 0]))
+                );
+            }
+        }
+    }
+
+    mod render {
+        use super::{
+            typestate::{Digraph, GraphNode},
+            Node, TreeLike,
+        };
+        use std::fmt::Write;
+
+        #[derive(Debug, Clone, Default)]
+        pub struct Options {
+            // deepest level drawn, the root being 0. None draws everything
+            pub max_depth: Option<usize>,
+            // children drawn per node before the rest are summed up in one
+            // `… n more` line. None draws them all
+            pub max_children: Option<usize>,
+        }
+
+        // draws the tree with box-drawing connectors, one node per line, the
+        // way the unix `tree` command does:
+        //
+        //   root
+        //   ├── src
+        //   │   └── main.rs
+        //   └── README.md
+        pub fn tree<N, F>(root: &N, label: F, options: &Options) -> String
+        where
+            N: TreeLike,
+            F: Fn(&N) -> String,
+        {
+            enum Line<'a, N> {
+                Node(&'a N, usize),
+                More(usize),
+            }
+
+            let mut out = String::new();
+            // each entry carries the prefix its line starts with and whether
+            // it's the last of its siblings
+            let mut stack = vec![(Line::Node(root, 0), String::new(), true)];
+
+            while let Some((line, prefix, last)) = stack.pop() {
+                let connector = match line {
+                    Line::Node(_, 0) => "",
+                    _ if last => "└── ",
+                    _ => "├── ",
+                };
+
+                let (node, depth) = match line {
+                    Line::More(n) => {
+                        let _ = writeln!(out, "{}{}… {} more", prefix, connector, n);
+                        continue;
+                    }
+                    Line::Node(node, depth) => (node, depth),
+                };
+                let _ = writeln!(out, "{}{}{}", prefix, connector, label(node));
+
+                if options.max_depth.is_some_and(|max| depth >= max) {
+                    continue;
+                }
+
+                let children = node.children();
+                let shown = options
+                    .max_children
+                    .map_or(children.len(), |max| max.min(children.len()));
+                let hidden = children.len() - shown;
+
+                let below = if depth == 0 {
+                    String::new()
+                } else if last {
+                    format!("{}    ", prefix)
+                } else {
+                    format!("{}│   ", prefix)
+                };
+
+                if hidden > 0 {
+                    stack.push((Line::More(hidden), below.clone(), true));
+                }
+                for (i, child) in children[..shown].iter().enumerate().rev() {
+                    let last = hidden == 0 && i + 1 == shown;
+                    stack.push((Line::Node(child, depth + 1), below.clone(), last));
+                }
+            }
+
+            out
+        }
+
+        pub fn node(root: &Node, options: &Options) -> String {
+            tree(root, |n| n.name.clone(), options)
+        }
+
+        // graphviz source for the graph, e.g. `dot -Tsvg`. vertices are
+        // declared in id order, then every edge
+        pub fn dot(graph: &Digraph) -> String {
+            let mut out = String::from("digraph {\n");
+
+            for id in graph.vertices() {
+                let _ = writeln!(
+                    out,
+                    "    n{} [label={}];",
+                    id.index(),
+                    quote(graph.name(id))
+                );
+            }
+            for from in graph.vertices() {
+                for to in graph.successors(from) {
+                    let _ = writeln!(out, "    n{} -> n{};", from.index(), to.index());
+                }
+            }
+
+            out.push_str("}\n");
+            out
+        }
+
+        pub fn graph_node(node: &GraphNode) -> String {
+            dot(&node.graph())
+        }
+
+        // a DOT double-quoted string
+        fn quote(name: &str) -> String {
+            format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+            use crate::stateful::{json, tests::fixture};
+
+            // compares against fixtures/golden/<name>. run with
+            // UPDATE_GOLDEN=1 to rewrite the files after a deliberate change
+            fn golden(name: &str, actual: &str) {
+                let path = fixture(&format!("golden/{}", name));
+                if std::env::var_os("UPDATE_GOLDEN").is_some() {
+                    std::fs::write(&path, actual).unwrap();
+                }
+                let expected = std::fs::read_to_string(&path).unwrap();
+                assert_eq!(actual, expected, "{} differs", name);
+            }
+
+            // one branch deeper than the rest, one wider, one empty
+            fn lopsided() -> Node {
+                let leaves = |prefix: &str| {
+                    (1..=4)
+                        .map(|i| Node::new(&format!("{}{}", prefix, i), vec![]))
+                        .collect()
+                };
+                Node::new(
+                    "root",
+                    vec![
+                        Node::new("a", vec![Node::new("a1", leaves("a1."))]),
+                        Node::new("b", leaves("b")),
+                        Node::new("c", vec![]),
+                    ],
+                )
+            }
+
+            #[test]
+            fn tree_fixture() {
+                let root: Node = json::from_file(fixture("tree.json")).unwrap();
+                golden("tree.txt", &node(&root, &Options::default()));
+            }
+
+            #[test]
+            fn tree_limits() {
+                golden("wide.txt", &node(&lopsided(), &Options::default()));
+
+                let options = Options {
+                    max_depth: Some(2),
+                    max_children: Some(2),
+                };
+                golden("wide_limited.txt", &node(&lopsided(), &options));
+
+                let options = Options {
+                    max_depth: Some(0),
+                    ..Options::default()
+                };
+                assert_eq!(node(&lopsided(), &options), "root\n");
+            }
+
+            #[test]
+            fn custom_labels() {
+                let out = tree(
+                    &lopsided(),
+                    |n: &Node| format!("{} ({})", n.name, n.children.len()),
+                    &Options {
+                        max_depth: Some(1),
+                        ..Options::default()
+                    },
+                );
+                assert_eq!(out, "root (3)\n├── a (1)\n├── b (4)\n└── c (0)\n");
+            }
+
+            #[test]
+            fn dot_fixtures() {
+                let dag: GraphNode = json::from_file(fixture("graph_dag.json")).unwrap();
+                golden("graph_dag.dot", &graph_node(&dag));
+
+                let cycle: GraphNode = json::from_file(fixture("graph_cycle.json")).unwrap();
+                golden("graph_cycle.dot", &graph_node(&cycle));
+            }
+
+            #[test]
+            fn dot_edges_once() {
+                // b written out twice, children and all
+                let b = || GraphNode::new("b", vec![GraphNode::new("c", vec![])]);
+                let out = graph_node(&GraphNode::new("a", vec![b(), b()]));
+                assert_eq!(out.matches("n0 -> n1;").count(), 1);
+                assert_eq!(out.matches("n1 -> n2;").count(), 1);
+            }
+
+            #[test]
+            fn dot_quotes_names() {
+                let node = GraphNode::new(r#"say "hi"\"#, vec![]);
+                assert!(graph_node(&node).contains(r#"n0 [label="say \"hi\"\\"];"#));
+            }
+        }
     }
 
-    trait TreeLike: Sized {
-        fn children(&self) -> &[Self];
+    mod query {
+        use super::Node;
+
+        // one `/`-separated piece of a pattern
+        #[derive(Debug, Clone, PartialEq)]
+        enum Segment {
+            // `**`: any number of names, none included
+            Any,
+            // a name, where `*` stands for any run of characters and `?` for
+            // exactly one
+            Glob(String),
+        }
+
+        // a path pattern like `root/src/**/*.rs`. the first segment matches
+        // the root's name, like in Node::get
+        #[derive(Debug, Clone, PartialEq)]
+        pub struct Pattern {
+            segments: Vec<Segment>,
+        }
+
+        impl Pattern {
+            pub fn new(pattern: &str) -> Self {
+                let segments = pattern
+                    .split('/')
+                    .filter(|s| !s.is_empty())
+                    .map(|s| match s {
+                        "**" => Segment::Any,
+                        glob => Segment::Glob(glob.into()),
+                    })
+                    .collect();
+                Pattern { segments }
+            }
+
+            // the states matching can be in, as positions in `segments`;
+            // `segments.len()` means the whole pattern has matched. a `**`
+            // can always be stepped over without consuming anything
+            fn closure(&self, mut states: Vec<usize>) -> Vec<usize> {
+                let mut i = 0;
+                while i < states.len() {
+                    let s = states[i];
+                    if self.segments.get(s) == Some(&Segment::Any) && !states.contains(&(s + 1)) {
+                        states.push(s + 1);
+                    }
+                    i += 1;
+                }
+                states
+            }
+
+            fn start(&self) -> Vec<usize> {
+                self.closure(vec![0])
+            }
+
+            fn step(&self, states: &[usize], name: &str) -> Vec<usize> {
+                let mut next = vec![];
+                for s in states {
+                    let to = match self.segments.get(*s) {
+                        Some(Segment::Any) => Some(*s),
+                        Some(Segment::Glob(glob)) if glob_match(glob, name) => Some(s + 1),
+                        _ => None,
+                    };
+                    if let Some(to) = to
+                        && !next.contains(&to)
+                    {
+                        next.push(to);
+                    }
+                }
+                self.closure(next)
+            }
+
+            fn accepts(&self, states: &[usize]) -> bool {
+                states.contains(&self.segments.len())
+            }
+
+            // whether a path of names, root first, matches
+            pub fn matches<'a>(&self, path: impl IntoIterator<Item = &'a str>) -> bool {
+                let states = path
+                    .into_iter()
+                    .fold(self.start(), |states, name| self.step(&states, name));
+                self.accepts(&states)
+            }
+        }
+
+        // `*` and `?` wildcards over one name. backtracks only to the last
+        // `*`, so it stays linear-ish instead of exponential
+        pub(super) fn glob_match(glob: &str, name: &str) -> bool {
+            let (glob, name): (Vec<char>, Vec<char>) =
+                (glob.chars().collect(), name.chars().collect());
+            let (mut g, mut n) = (0, 0);
+            // where the last `*` was, and where in `name` it started matching
+            let mut star: Option<(usize, usize)> = None;
+
+            while n < name.len() {
+                match glob.get(g) {
+                    Some('*') => {
+                        star = Some((g, n));
+                        g += 1;
+                    }
+                    Some(c) if *c == '?' || *c == name[n] => {
+                        g += 1;
+                        n += 1;
+                    }
+                    // let the last `*` swallow one more character
+                    _ => match star {
+                        Some((sg, sn)) => {
+                            star = Some((sg, sn + 1));
+                            g = sg + 1;
+                            n = sn + 1;
+                        }
+                        None => return false,
+                    },
+                }
+            }
+
+            glob[g..].iter().all(|c| *c == '*')
+        }
+
+        // a Pattern, plus a test on the matched nodes themselves
+        pub struct Query<'p> {
+            pattern: Pattern,
+            predicate: Box<dyn Fn(&Node) -> bool + 'p>,
+        }
+
+        impl<'p> Query<'p> {
+            pub fn new(pattern: &str) -> Self {
+                Query {
+                    pattern: Pattern::new(pattern),
+                    predicate: Box::new(|_| true),
+                }
+            }
+
+            // keeps only the matches `predicate` holds for. chained calls
+            // must all hold
+            pub fn filter<P>(self, predicate: P) -> Self
+            where
+                P: Fn(&Node) -> bool + 'p,
+            {
+                let before = self.predicate;
+                Query {
+                    pattern: self.pattern,
+                    predicate: Box::new(move |n| before(n) && predicate(n)),
+                }
+            }
+
+            // every match under `root` with its full path, pre-order.
+            // subtrees the pattern can no longer match are not walked
+            pub fn run<'a>(&self, root: &'a Node) -> impl Iterator<Item = (String, &'a Node)> {
+                let states = self.pattern.step(&self.pattern.start(), &root.name);
+                let mut stack = vec![(root, root.name.clone(), states)];
+
+                std::iter::from_fn(move || {
+                    while let Some((node, path, states)) = stack.pop() {
+                        for child in node.children.iter().rev() {
+                            let next = self.pattern.step(&states, &child.name);
+                            if !next.is_empty() {
+                                stack.push((child, format!("{}/{}", path, child.name), next));
+                            }
+                        }
+                        if self.pattern.accepts(&states) && (self.predicate)(node) {
+                            return Some((path, node));
+                        }
+                    }
+                    None
+                })
+            }
+        }
+
+        impl Node {
+            // `path` names the root first, e.g. `root/src/main.rs`. with
+            // repeated names among siblings the first one is taken
+            pub fn get(&self, path: &str) -> Option<&Node> {
+                let mut names = path.split('/').filter(|s| !s.is_empty());
+                if names.next()? != self.name {
+                    return None;
+                }
+                names.try_fold(self, |node, name| {
+                    node.children.iter().find(|c| c.name == name)
+                })
+            }
+
+            pub fn get_mut(&mut self, path: &str) -> Option<&mut Node> {
+                let mut names = path.split('/').filter(|s| !s.is_empty());
+                if names.next()? != self.name {
+                    return None;
+                }
+                names.try_fold(self, |node, name| {
+                    node.children.iter_mut().find(|c| c.name == name)
+                })
+            }
+
+            // shorthand for Query::new(pattern).run(self)
+            pub fn glob<'a>(&'a self, pattern: &str) -> Vec<(String, &'a Node)> {
+                Query::new(pattern).run(self).collect()
+            }
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+            use crate::stateful::{
+                json,
+                tests::{fixture, leaf},
+            };
+
+            fn sample() -> Node {
+                let mut root: Node = json::from_file(fixture("tree.json")).unwrap();
+                let src = root.get_mut("root/src").unwrap();
+                src.children
+                    .push(Node::new("tree", vec![leaf("mod.rs"), leaf("walk.rs")]));
+                root
+            }
+
+            fn paths(found: Vec<(String, &Node)>) -> Vec<String> {
+                found.into_iter().map(|(path, _)| path).collect()
+            }
+
+            #[test]
+            fn get_by_path() {
+                let mut root = sample();
+
+                assert_eq!(root.get("root").unwrap().name, "root");
+                assert_eq!(root.get("root/src/tree/mod.rs").unwrap().name, "mod.rs");
+                assert_eq!(root.get("/root/src/").unwrap().children.len(), 3);
+                assert!(root.get("src/main.rs").is_none());
+                assert!(root.get("root/src/nope").is_none());
+                assert!(root.get("").is_none());
+
+                root.get_mut("root/README.md").unwrap().name = "README".into();
+                assert!(root.get("root/README").is_some());
+            }
+
+            #[test]
+            fn globs() {
+                let root = sample();
+
+                assert_eq!(paths(root.glob("root/*")), ["root/src", "root/README.md"]);
+                assert_eq!(
+                    paths(root.glob("root/**/*.rs")),
+                    [
+                        "root/src/main.rs",
+                        "root/src/lib.rs",
+                        "root/src/tree/mod.rs",
+                        "root/src/tree/walk.rs",
+                    ]
+                );
+                assert_eq!(
+                    paths(root.glob("root/src/**/mod.rs")),
+                    ["root/src/tree/mod.rs"]
+                );
+                assert_eq!(paths(root.glob("**/?ib.*")), ["root/src/lib.rs"]);
+                assert_eq!(paths(root.glob("r*t/**/tree")), ["root/src/tree"]);
+                assert_eq!(root.glob("**").len(), 8);
+                assert!(root.glob("root/*.rs").is_empty());
+                assert!(root.glob("other/**").is_empty());
+            }
+
+            #[test]
+            fn predicates() {
+                let root = sample();
+                let leaves = Query::new("root/**").filter(|n| n.children.is_empty());
+                let found: Vec<_> = leaves.run(&root).map(|(path, _)| path).collect();
+                assert_eq!(found.len(), 5);
+                assert!(found.contains(&"root/README.md".to_string()));
+
+                let big = Query::new("**")
+                    .filter(|n| !n.children.is_empty())
+                    .filter(|n| n.name.len() > 3);
+                let found: Vec<_> = big.run(&root).map(|(path, _)| path).collect();
+                assert_eq!(found, ["root", "root/src/tree"]);
+            }
+
+            #[test]
+            fn glob_matching() {
+                assert!(glob_match("*", ""));
+                assert!(glob_match("*.rs", "main.rs"));
+                assert!(glob_match("m*n*.rs", "main.rs"));
+                assert!(glob_match("a*b*c", "aXbYbZc"));
+                assert!(!glob_match("*.rs", "main.rsx"));
+                assert!(!glob_match("?", ""));
+                assert!(Pattern::new("a/**/b").matches(["a", "b"]));
+                assert!(Pattern::new("a/**/b").matches(["a", "x", "y", "b"]));
+                assert!(!Pattern::new("a/**/b").matches(["a", "b", "c"]));
+            }
+        }
     }
 
-    impl TreeLike for Node {
-        fn children(&self) -> &[Self] {
-            &self.children
+    mod zipper {
+        use super::Node;
+        use std::mem;
+
+        // what's left of a parent once we've gone down into one of its
+        // children: the parent with its children taken out, and the siblings
+        // either side of the child. `right` is stored nearest last so moving
+        // either way is a pop and a push
+        #[derive(Debug)]
+        struct Crumb {
+            parent: Node,
+            left: Vec<Node>,
+            right: Vec<Node>,
+        }
+
+        // a cursor that owns the tree and takes it apart along the path to
+        // the focus. moving and editing only touch the nodes on that path and
+        // their sibling lists; nothing is cloned
+        #[derive(Debug)]
+        pub struct Zipper {
+            focus: Node,
+            path: Vec<Crumb>,
+        }
+
+        impl Zipper {
+            pub fn new(root: Node) -> Self {
+                Zipper {
+                    focus: root,
+                    path: vec![],
+                }
+            }
+
+            pub fn focus(&self) -> &Node {
+                &self.focus
+            }
+
+            pub fn focus_mut(&mut self) -> &mut Node {
+                &mut self.focus
+            }
+
+            pub fn depth(&self) -> usize {
+                self.path.len()
+            }
+
+            // where the focus sits among its siblings, None at the root
+            pub fn index(&self) -> Option<usize> {
+                self.path.last().map(|crumb| crumb.left.len())
+            }
+
+            pub fn is_root(&self) -> bool {
+                self.path.is_empty()
+            }
+
+            // the movements hand back None and leave the zipper where it was
+            // if there's nowhere to go, so they chain with `?`
+            pub fn down(&mut self, i: usize) -> Option<&mut Self> {
+                if i >= self.focus.children.len() {
+                    return None;
+                }
+
+                let mut left = mem::take(&mut self.focus.children);
+                let mut right = left.split_off(i + 1);
+                right.reverse();
+                let child = left.pop()?;
+
+                let parent = mem::replace(&mut self.focus, child);
+                self.path.push(Crumb {
+                    parent,
+                    left,
+                    right,
+                });
+                Some(self)
+            }
+
+            pub fn up(&mut self) -> Option<&mut Self> {
+                let Crumb {
+                    parent,
+                    mut left,
+                    right,
+                } = self.path.pop()?;
+
+                left.reserve(right.len() + 1);
+                left.push(mem::replace(&mut self.focus, parent));
+                left.extend(right.into_iter().rev());
+                self.focus.children = left;
+                Some(self)
+            }
+
+            pub fn left(&mut self) -> Option<&mut Self> {
+                let crumb = self.path.last_mut()?;
+                let sibling = crumb.left.pop()?;
+                crumb.right.push(mem::replace(&mut self.focus, sibling));
+                Some(self)
+            }
+
+            pub fn right(&mut self) -> Option<&mut Self> {
+                let crumb = self.path.last_mut()?;
+                let sibling = crumb.right.pop()?;
+                crumb.left.push(mem::replace(&mut self.focus, sibling));
+                Some(self)
+            }
+
+            pub fn root(&mut self) -> &mut Self {
+                while self.up().is_some() {}
+                self
+            }
+
+            // puts the tree back together
+            pub fn into_node(mut self) -> Node {
+                self.root();
+                self.focus
+            }
+
+            // swaps the focused subtree for `node`, handing back the old one
+            pub fn replace(&mut self, node: Node) -> Node {
+                mem::replace(&mut self.focus, node)
+            }
+
+            // a new sibling just before the focus. the focus stays put.
+            // the root has no siblings, so hands `node` back there
+            pub fn insert_left(&mut self, node: Node) -> Result<(), Node> {
+                match self.path.last_mut() {
+                    Some(crumb) => {
+                        crumb.left.push(node);
+                        Ok(())
+                    }
+                    None => Err(node),
+                }
+            }
+
+            pub fn insert_right(&mut self, node: Node) -> Result<(), Node> {
+                match self.path.last_mut() {
+                    Some(crumb) => {
+                        crumb.right.push(node);
+                        Ok(())
+                    }
+                    None => Err(node),
+                }
+            }
+
+            // a new `i`th child of the focus, clamped to the end
+            pub fn insert_child(&mut self, i: usize, node: Node) {
+                let i = i.min(self.focus.children.len());
+                self.focus.children.insert(i, node);
+            }
+
+            // takes the focused subtree out. the focus moves to the next
+            // sibling, else the previous one, else the parent. the root
+            // can't be deleted
+            pub fn delete(&mut self) -> Option<Node> {
+                let crumb = self.path.last_mut()?;
+                let next = match crumb.right.pop().or_else(|| crumb.left.pop()) {
+                    Some(sibling) => sibling,
+                    None => {
+                        let Crumb { parent, .. } = self.path.pop()?;
+                        parent
+                    }
+                };
+                Some(mem::replace(&mut self.focus, next))
+            }
+        }
+
+        impl From<Node> for Zipper {
+            fn from(node: Node) -> Self {
+                Zipper::new(node)
+            }
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+            use crate::stateful::tests::leaf;
+
+            fn sample() -> Zipper {
+                Zipper::new(crate::stateful::tests::sample())
+            }
+
+            #[test]
+            fn navigation() {
+                let mut z = sample();
+
+                assert_eq!(z.down(0).unwrap().down(1).unwrap().focus().name, "d");
+                assert_eq!((z.depth(), z.index()), (2, Some(1)));
+                assert!(z.right().is_none());
+                assert_eq!(z.focus().name, "d");
+                assert_eq!(z.left().unwrap().focus().name, "c");
+                assert_eq!(z.up().unwrap().right().unwrap().focus().name, "e");
+                assert!(z.down(0).is_none());
+                assert_eq!(z.root().focus().name, "a");
+                assert!(z.is_root() && z.up().is_none() && z.left().is_none());
+
+                assert_eq!(z.into_node(), sample().into_node());
+            }
+
+            #[test]
+            fn edits() {
+                let mut z = sample();
+                z.down(0).unwrap().down(0);
+
+                z.focus_mut().name = "C".into();
+                z.insert_left(leaf("x")).unwrap();
+                z.insert_right(leaf("y")).unwrap();
+                assert_eq!(z.index(), Some(1));
+
+                z.up().unwrap().right();
+                let old = z.replace(Node::new("f", vec![leaf("g")]));
+                assert_eq!(old, leaf("e"));
+                z.insert_child(5, leaf("h"));
+
+                let expected = Node::new(
+                    "a",
+                    vec![
+                        Node::new("b", vec![leaf("x"), leaf("C"), leaf("y"), leaf("d")]),
+                        Node::new("f", vec![leaf("g"), leaf("h")]),
+                    ],
+                );
+                assert_eq!(z.into_node(), expected);
+            }
+
+            #[test]
+            fn delete_moves_focus() {
+                let mut z = sample();
+
+                assert_eq!(z.insert_left(leaf("x")), Err(leaf("x")));
+                assert!(z.delete().is_none());
+
+                z.down(0).unwrap().down(0);
+                assert_eq!(z.delete().unwrap().name, "c");
+                assert_eq!(z.focus().name, "d");
+                assert_eq!(z.delete().unwrap().name, "d");
+                assert_eq!(z.focus().name, "b");
+                assert!(z.focus().children.is_empty());
+
+                z.right();
+                assert_eq!(z.delete().unwrap().name, "e");
+                assert_eq!(z.focus().name, "b");
+                assert_eq!(z.into_node(), Node::new("a", vec![leaf("b")]));
+            }
+
+            #[test]
+            fn million_deep_chain() {
+                let mut z = Zipper::new(crate::stateful::tests::chain(1_000_000));
+                while z.down(0).is_some() {}
+                assert_eq!(z.depth(), 999_999);
+
+                z.focus_mut().name = "bottom".into();
+                let node = z.into_node();
+                assert_eq!(node.iter_pre().last().unwrap().name, "bottom");
+            }
         }
     }
 
-    struct State {
-        n: usize,
-        names: Vec<String>,
+    mod disk {
+        use super::{query::glob_match, Node};
+        use std::{
+            fs, io,
+            path::{Path, PathBuf},
+        };
+
+        #[derive(Debug, Clone, Default)]
+        pub struct DirOptions {
+            // deepest level read, the directory itself being 0. directories
+            // at the limit show up empty. None reads everything
+            pub max_depth: Option<usize>,
+            // otherwise a symlink is a leaf, whatever it points at
+            pub follow_symlinks: bool,
+            // names to leave out, with the same `*` and `?` wildcards as
+            // Node::glob, e.g. "target" or "*.o"
+            pub ignore: Vec<String>,
+            // fill in Node::size for files
+            pub sizes: bool,
+        }
+
+        impl Node {
+            // mirrors the directory at `path`, children sorted by name. the
+            // root is named after the last component of `path`. only the root
+            // has to be readable: anything below it that can't be read shows
+            // up as a leaf
+            pub fn from_dir(path: impl AsRef<Path>, options: &DirOptions) -> io::Result<Node> {
+                enum Frame {
+                    Open(PathBuf, String, usize),
+                    // a directory, its size and how many children it had
+                    Close(String, Option<u64>, usize),
+                }
+
+                let path = path.as_ref();
+                let name = path.file_name().map_or_else(
+                    || path.display().to_string(),
+                    |n| n.to_string_lossy().into_owned(),
+                );
+
+                // the directories open right now, root first, so a followed
+                // symlink back up the tree doesn't loop forever. the same
+                // directory reached twice some other way is read both times
+                let mut open: Vec<PathBuf> = vec![];
+                let mut done: Vec<Node> = vec![];
+                let mut stack = vec![Frame::Open(path.to_path_buf(), name, 0)];
+
+                while let Some(frame) = stack.pop() {
+                    let (path, name, depth) = match frame {
+                        Frame::Open(path, name, depth) => (path, name, depth),
+                        Frame::Close(name, size, n) => {
+                            open.pop();
+                            let children = done.split_off(done.len() - n);
+                            let mut node = Node::new(&name, children);
+                            node.size = size;
+                            done.push(node);
+                            continue;
+                        }
+                    };
+
+                    let meta = if options.follow_symlinks {
+                        // a dangling link has nothing to follow
+                        fs::metadata(&path).or_else(|_| fs::symlink_metadata(&path))
+                    } else {
+                        fs::symlink_metadata(&path)
+                    };
+                    let leaf = Node::new(&name, vec![]);
+                    let meta = match meta {
+                        Ok(meta) => meta,
+                        Err(e) if depth == 0 => return Err(e),
+                        Err(_) => {
+                            done.push(leaf);
+                            continue;
+                        }
+                    };
+                    let size = (options.sizes && meta.is_file()).then_some(meta.len());
+
+                    let within = options.max_depth.is_none_or(|max| depth < max);
+                    let read = |path: &Path| -> io::Result<_> {
+                        let canonical = fs::canonicalize(path)?;
+                        if open.contains(&canonical) {
+                            return Ok(None);
+                        }
+                        Ok(Some((canonical, fs::read_dir(path)?)))
+                    };
+                    let (canonical, listing) = match (meta.is_dir() && within).then(|| read(&path))
+                    {
+                        Some(Ok(Some(read))) => read,
+                        Some(Err(e)) if depth == 0 => return Err(e),
+                        _ => {
+                            let mut node = leaf;
+                            node.size = size;
+                            done.push(node);
+                            continue;
+                        }
+                    };
+
+                    // an entry that vanished while being listed is left out
+                    let mut entries = vec![];
+                    for entry in listing.flatten() {
+                        let name = entry.file_name().to_string_lossy().into_owned();
+                        if !options.ignore.iter().any(|glob| glob_match(glob, &name)) {
+                            entries.push((name, entry.path()));
+                        }
+                    }
+                    entries.sort();
+
+                    open.push(canonical);
+                    stack.push(Frame::Close(name, size, entries.len()));
+                    stack.extend(
+                        entries
+                            .into_iter()
+                            .rev()
+                            .map(|(name, path)| Frame::Open(path, name, depth + 1)),
+                    );
+                }
+
+                Ok(done.pop().expect("the walk ends with the root built"))
+            }
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+            use crate::stateful::{
+                tree::walk_pre,
+                visitor::{Counter, Index},
+            };
+            use std::collections::HashSet;
+
+            // dir/
+            //   a.txt        5 bytes
+            //   sub/
+            //     a.txt      3 bytes
+            //     deep/
+            //       b.txt    2 bytes
+            //   target/
+            //     junk.o     7 bytes
+            fn sample() -> tempfile::TempDir {
+                let dir = tempfile::tempdir().unwrap();
+                let root = dir.path();
+                fs::create_dir_all(root.join("sub/deep")).unwrap();
+                fs::create_dir_all(root.join("target")).unwrap();
+                fs::write(root.join("a.txt"), "hello").unwrap();
+                fs::write(root.join("sub/a.txt"), "abc").unwrap();
+                fs::write(root.join("sub/deep/b.txt"), "xy").unwrap();
+                fs::write(root.join("target/junk.o"), "1234567").unwrap();
+                dir
+            }
+
+            fn paths(node: &Node) -> Vec<String> {
+                let mut paths = vec![];
+                let mut stack = vec![(node, String::new())];
+                while let Some((node, prefix)) = stack.pop() {
+                    let path = format!("{}{}", prefix, node.name);
+                    for child in node.children.iter().rev() {
+                        stack.push((child, format!("{}/", path)));
+                    }
+                    paths.push(path);
+                }
+                paths[1..]
+                    .iter()
+                    .map(|p| p.split_once('/').unwrap().1.to_string())
+                    .collect()
+            }
+
+            #[test]
+            fn mirrors_directory() {
+                let dir = sample();
+                let node = Node::from_dir(dir.path(), &DirOptions::default()).unwrap();
+
+                assert_eq!(node.name, dir.path().file_name().unwrap().to_string_lossy());
+                assert_eq!(
+                    paths(&node),
+                    [
+                        "a.txt",
+                        "sub",
+                        "sub/a.txt",
+                        "sub/deep",
+                        "sub/deep/b.txt",
+                        "target",
+                        "target/junk.o",
+                    ]
+                );
+                assert!(node.iter_pre().all(|n| n.size.is_none()));
+            }
+
+            #[test]
+            fn options() {
+                let dir = sample();
+                let options = DirOptions {
+                    max_depth: Some(1),
+                    ignore: vec!["target".into(), "*.o".into()],
+                    ..DirOptions::default()
+                };
+                let node = Node::from_dir(dir.path(), &options).unwrap();
+                assert_eq!(paths(&node), ["a.txt", "sub"]);
+            }
+
+            #[test]
+            fn disk_usage_and_duplicates() {
+                let dir = sample();
+                let options = DirOptions {
+                    sizes: true,
+                    ..DirOptions::default()
+                };
+                let node = Node::from_dir(dir.path(), &options).unwrap();
+
+                let mut counter = Counter::default();
+                walk_pre(&node, &mut counter);
+                assert_eq!((counter.bytes, counter.leaves), (17, 4));
+
+                let mut index = Index::default();
+                walk_pre(&node, &mut index);
+                assert_eq!(index.duplicates, HashSet::from(["a.txt".to_string()]));
+
+                assert_eq!(
+                    node.get(&format!("{}/sub/a.txt", node.name)).unwrap().size,
+                    Some(3)
+                );
+                assert_eq!(node.get(&format!("{}/sub", node.name)).unwrap().size, None);
+            }
+
+            #[test]
+            fn missing_directory() {
+                let dir = sample();
+                let err = Node::from_dir(dir.path().join("nope"), &DirOptions::default());
+                assert_eq!(err.unwrap_err().kind(), io::ErrorKind::NotFound);
+            }
+
+            #[cfg(unix)]
+            #[test]
+            fn symlinks() {
+                let dir = sample();
+                let root = dir.path();
+                std::os::unix::fs::symlink(root.join("sub/deep"), root.join("link")).unwrap();
+                // back up to the root: followed, this would never end
+                std::os::unix::fs::symlink(root, root.join("sub/up")).unwrap();
+
+                let node = Node::from_dir(root, &DirOptions::default()).unwrap();
+                assert!(paths(&node).contains(&"link".to_string()));
+                assert!(!paths(&node).contains(&"link/b.txt".to_string()));
+
+                let options = DirOptions {
+                    follow_symlinks: true,
+                    ignore: vec!["target".into()],
+                    ..DirOptions::default()
+                };
+                let node = Node::from_dir(root, &options).unwrap();
+                let paths = paths(&node);
+                assert!(paths.contains(&"link/b.txt".to_string()));
+                // read through `link` and again where it really is
+                assert!(paths.contains(&"sub/deep/b.txt".to_string()));
+                assert!(paths.contains(&"sub/up".to_string()));
+                assert!(!paths.iter().any(|p| p.starts_with("sub/up/")));
+
+                // links that can't be followed are leaves, not errors
+                std::os::unix::fs::symlink(root.join("nope"), root.join("dangling")).unwrap();
+                std::os::unix::fs::symlink(root.join("self"), root.join("self")).unwrap();
+                let node = Node::from_dir(root, &options).unwrap();
+                let leaves = |name: &str| {
+                    node.children
+                        .iter()
+                        .any(|c| c.name == name && c.children.is_empty())
+                };
+                assert!(leaves("dangling") && leaves("self"));
+            }
+        }
     }
 
-    impl Visitor<Node> for State {
-        fn process(&mut self, node: &Node) {
-            self.names.push(node.name.clone());
+    mod merkle {
+        use super::Node;
+        use std::{
+            collections::HashMap,
+            hash::{DefaultHasher, Hash, Hasher},
+        };
+
+        // a node's hash: its name, then its children's hashes in order. so
+        // two subtrees hash the same when they have the same names in the
+        // same shape. Node::size is left out. the hasher is std's, which
+        // may change between compiler versions, so don't store these
+        pub fn combine(name: &str, children: impl IntoIterator<Item = u64>) -> u64 {
+            let mut hasher = DefaultHasher::new();
+            name.hash(&mut hasher);
+            for child in children {
+                child.hash(&mut hasher);
+            }
+            hasher.finish()
+        }
+
+        // every subtree of a tree hashed, indexed in pre-order: a subtree
+        // starting at `i` takes up `i..i + size[i]`
+        pub struct Digests<'a> {
+            pub nodes: Vec<&'a Node>,
+            pub parent: Vec<Option<usize>>,
+            pub size: Vec<usize>,
+            pub hashes: Vec<u64>,
         }
-    }
 
-    impl Node {
-        pub fn dfs(&self) {
-            let mut state = State {
-                n: 0,
-                names: vec![],
-            };
-            walk_pre(self, &mut state);
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct Duplicate {
+            // nodes in each copy
+            pub size: usize,
+            // where the copies are, as `a/b/c` paths from the root
+            pub paths: Vec<String>,
         }
-    }
 
-    fn walk_pre<N, V>(node: &N, visitor: &mut V)
-    where
-        N: TreeLike,
-        V: Visitor<N>,
-    {
-        visitor.process(node);
+        impl<'a> Digests<'a> {
+            pub fn new(root: &'a Node) -> Self {
+                let mut digests = Digests {
+                    nodes: vec![],
+                    parent: vec![],
+                    size: vec![],
+                    hashes: vec![],
+                };
+                let mut stack: Vec<(&Node, Option<usize>)> = vec![(root, None)];
+                while let Some((node, parent)) = stack.pop() {
+                    let i = digests.nodes.len();
+                    digests.nodes.push(node);
+                    digests.parent.push(parent);
+                    stack.extend(node.children.iter().rev().map(|c| (c, Some(i))));
+                }
 
-        for child in node.children() {
-            walk_pre(child, visitor);
-        }
-    }
+                // children come after their parent, so going backwards every
+                // child is done before the node that needs it
+                let n = digests.nodes.len();
+                digests.size = vec![1; n];
+                digests.hashes = vec![0; n];
+                for i in (0..n).rev() {
+                    let mut kids = vec![];
+                    let mut k = i + 1;
+                    for _ in 0..digests.nodes[i].children.len() {
+                        kids.push(digests.hashes[k]);
+                        digests.size[i] += digests.size[k];
+                        k += digests.size[k];
+                    }
+                    digests.hashes[i] = combine(&digests.nodes[i].name, kids);
+                }
+                digests
+            }
 
-    mod closure {
-        pub fn run() {
-            let mut state = vec![];
+            pub fn root(&self) -> u64 {
+                self.hashes[0]
+            }
 
-            // must be `mut` because closure mutates captured state (FnMut)
-            // FnMut is a type which mutates closure state
-            let mut g = |n| {
-                for x in 1..n {
-                    state.push(x);
+            // indices of `i`'s children: right after it, one subtree after
+            // the other
+            pub fn children(&self, i: usize) -> impl Iterator<Item = usize> + '_ {
+                let mut next = i + 1;
+                (0..self.nodes[i].children.len()).map(move |_| {
+                    let child = next;
+                    next += self.size[child];
+                    child
+                })
+            }
+
+            // whether the subtree at `i` has the same names in the same shape
+            // as `other`'s at `j`, sizes aside. different hashes settle it;
+            // equal ones are checked node by node, since 64 bits can collide.
+            // one pre-order run against the other, so no recursion
+            pub fn same(&self, i: usize, other: &Digests, j: usize) -> bool {
+                let size = self.size[i];
+                self.hashes[i] == other.hashes[j]
+                    && other.size[j] == size
+                    && (0..size).all(|k| {
+                        let (a, b) = (self.nodes[i + k], other.nodes[j + k]);
+                        a.name == b.name && a.children.len() == b.children.len()
+                    })
+            }
+
+            pub fn path(&self, mut i: usize) -> String {
+                let mut names = vec![self.nodes[i].name.as_str()];
+                while let Some(parent) = self.parent[i] {
+                    names.push(&self.nodes[parent].name);
+                    i = parent;
                 }
-            };
+                names.reverse();
+                names.join("/")
+            }
 
-            g(5);
+            // subtrees of at least `min_size` nodes that show up more than
+            // once, biggest first. copies inside a bigger copy aren't listed
+            // again, unless they are also found outside of it
+            pub fn duplicates(&self, min_size: usize) -> Vec<Duplicate> {
+                // a hash bucket can hold different subtrees if 64 bits collide,
+                // so it's split by what `same` compares: the name, and children
+                // that are pairwise copies. children come first, so that is
+                // their group ids rather than another scan of the subtree
+                let mut ids: HashMap<(u64, &str, Vec<usize>), usize> = HashMap::new();
+                let mut group_of = vec![0; self.nodes.len()];
+                let mut groups: Vec<Vec<usize>> = vec![];
+                for i in (0..self.nodes.len()).rev() {
+                    let children = self.children(i).map(|c| group_of[c]).collect();
+                    let key = (self.hashes[i], self.nodes[i].name.as_str(), children);
+                    let group = *ids.entry(key).or_insert_with(|| {
+                        groups.push(vec![]);
+                        groups.len() - 1
+                    });
+                    groups[group].push(i);
+                    group_of[i] = group;
+                }
+                for group in &mut groups {
+                    group.reverse();
+                }
+                let copied = |i: usize| groups[group_of[i]].len() > 1;
+
+                let mut found: Vec<Duplicate> = groups
+                    .iter()
+                    .filter(|group| group.len() > 1 && self.size[group[0]] >= min_size)
+                    .filter(|group| group.iter().any(|i| !self.parent[*i].is_some_and(copied)))
+                    .map(|group| Duplicate {
+                        size: self.size[group[0]],
+                        paths: group.iter().map(|i| self.path(*i)).collect(),
+                    })
+                    .collect();
+                found.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.paths.cmp(&b.paths)));
+                found
+            }
         }
-    }
 
-    mod var {
-        use super::*;
+        impl Node {
+            // the Merkle hash of the whole tree, see `combine`
+            pub fn digest(&self) -> u64 {
+                Digests::new(self).root()
+            }
 
-        pub fn dfs(node: &Node, names: &mut HashSet<String>) {
-            names.insert(node.name.clone());
+            // same names in the same shape, sizes ignored, see Digests::same
+            pub fn same_shape(&self, other: &Node) -> bool {
+                Digests::new(self).same(0, &Digests::new(other), 0)
+            }
+        }
 
-            for child in &node.children {
-                dfs(child, names);
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+            use crate::stateful::tests::leaf;
+
+            fn lib() -> Node {
+                Node::new(
+                    "lib",
+                    vec![leaf("mod.rs"), Node::new("util", vec![leaf("io.rs")])],
+                )
+            }
+
+            #[test]
+            fn hashes_follow_structure() {
+                assert_eq!(lib().digest(), lib().digest());
+
+                let mut sized = lib();
+                sized.size = Some(10);
+                assert_eq!(sized.digest(), lib().digest());
+                assert!(sized.same_shape(&lib()));
+
+                let mut renamed = lib();
+                renamed.children[1].children[0].name = "fs.rs".into();
+                assert_ne!(renamed.digest(), lib().digest());
+                assert!(!renamed.same_shape(&lib()));
+
+                // the same names, differently nested
+                let flat = Node::new("lib", vec![leaf("mod.rs"), leaf("util"), leaf("io.rs")]);
+                assert_ne!(flat.digest(), lib().digest());
+                let swapped = Node::new(
+                    "lib",
+                    vec![Node::new("util", vec![leaf("io.rs")]), leaf("mod.rs")],
+                );
+                assert_ne!(swapped.digest(), lib().digest());
+
+                let lib = lib();
+                let digests = Digests::new(&lib);
+                assert_eq!(digests.size, [4, 1, 2, 1]);
+                assert_eq!(digests.hashes[3], leaf("io.rs").digest());
+                assert_eq!(digests.path(3), "lib/util/io.rs");
+            }
+
+            #[test]
+            fn duplicate_subtrees() {
+                let root = Node::new(
+                    "repo",
+                    vec![
+                        Node::new("a", vec![lib(), leaf("README")]),
+                        Node::new("b", vec![lib(), leaf("README")]),
+                        Node::new("vendor", vec![Node::new("util", vec![leaf("io.rs")])]),
+                    ],
+                );
+                let digests = Digests::new(&root);
+
+                assert_eq!(
+                    digests.duplicates(2),
+                    [
+                        Duplicate {
+                            size: 4,
+                            paths: vec!["repo/a/lib".into(), "repo/b/lib".into()],
+                        },
+                        Duplicate {
+                            size: 2,
+                            paths: vec![
+                                "repo/a/lib/util".into(),
+                                "repo/b/lib/util".into(),
+                                "repo/vendor/util".into(),
+                            ],
+                        },
+                    ]
+                );
+
+                // leaves too. README is listed: a and b differ, so it isn't
+                // inside a bigger copy. io.rs always is
+                let leaves = digests.duplicates(1);
+                assert_eq!(leaves.len(), 3);
+                assert_eq!(leaves[2].paths, ["repo/a/README", "repo/b/README"]);
+
+                // every hash colliding changes nothing
+                let mut colliding = Digests::new(&root);
+                colliding.hashes.fill(0);
+                assert_eq!(colliding.duplicates(1), leaves);
+            }
+
+            #[test]
+            fn deep_trees() {
+                let root = crate::stateful::tests::chain(1_000_000);
+                let digests = Digests::new(&root);
+                assert_eq!(digests.size[0], 1_000_000);
+                assert!(root.same_shape(&crate::stateful::tests::chain(1_000_000)));
+                assert!(digests.duplicates(2).is_empty());
             }
         }
     }
 
-    mod hof {
-        use super::*;
+    mod shared {
+        use super::Node;
+        use parking_lot::RwLock;
+        use std::{mem, sync::Arc};
+
+        // a handle to one node of a tree that threads share. every node has
+        // its own lock, so readers of a subtree don't get in each other's way
+        // and a writer only holds up the one node it is changing. cloning
+        // the handle doesn't clone the node
+        #[derive(Clone)]
+        pub struct SharedNode(Arc<RwLock<Inner>>);
+
+        struct Inner {
+            name: String,
+            size: Option<u64>,
+            children: Vec<SharedNode>,
+        }
 
-        pub fn dfs<F>(node: &Node, f: &mut F)
-        where
-            F: FnMut(&Node),
-        {
-            f(node);
+        // the same trick as Node's Drop: the last handle to a deep chain
+        // would otherwise drop it one stack frame per level
+        impl Drop for Inner {
+            fn drop(&mut self) {
+                let mut stack = mem::take(&mut self.children);
 
-            for child in &node.children {
-                dfs(child, f);
+                while let Some(child) = stack.pop() {
+                    if let Some(lock) = Arc::into_inner(child.0) {
+                        stack.append(&mut lock.into_inner().children);
+                    }
+                }
             }
         }
 
-        pub fn uniq_names(root: &Node) {
-            let mut names = HashSet::<String>::new();
+        impl SharedNode {
+            fn from_parts(name: String, size: Option<u64>, children: Vec<SharedNode>) -> Self {
+                SharedNode(Arc::new(RwLock::new(Inner {
+                    name,
+                    size,
+                    children,
+                })))
+            }
 
-            let mut process = |node: &Node| {
-                names.insert(node.name.clone());
-            };
+            // every edit below takes exactly one node's write lock and does
+            // all of its work under it. that is what keeps snapshots whole
 
-            dfs(root, &mut process);
-        }
-    }
+            pub fn name(&self) -> String {
+                self.0.read().name.clone()
+            }
 
-    mod typed {
-        use super::*;
+            pub fn size(&self) -> Option<u64> {
+                self.0.read().size
+            }
 
-        #[derive(Default, Debug)]
-        pub struct State {
-            pub names: HashSet<String>,
-            pub total: usize,
-        }
+            // handles to the children as they are right now
+            pub fn children(&self) -> Vec<SharedNode> {
+                self.0.read().children.clone()
+            }
 
-        pub fn dfs(node: &Node, state: &mut State) {
-            state.names.insert(node.name.clone());
-            state.total += 1;
+            pub fn child(&self, index: usize) -> Option<SharedNode> {
+                self.0.read().children.get(index).cloned()
+            }
 
-            for child in &node.children {
-                dfs(child, state);
+            pub fn rename(&self, name: &str) {
+                self.0.write().name = name.into();
             }
-        }
-    }
 
-    mod visitor {
-        use super::*;
+            pub fn set_size(&self, size: Option<u64>) {
+                self.0.write().size = size;
+            }
 
-        // two independent types
-        #[derive(Default, Debug)]
-        pub struct Index {
-            pub names: HashSet<String>,
-        }
+            // panics if `index` is past the end, like Vec::insert
+            pub fn insert_child(&self, index: usize, node: Node) -> SharedNode {
+                let child = SharedNode::from(node);
+                self.0.write().children.insert(index, child.clone());
+                child
+            }
 
-        #[derive(Default, Debug)]
-        pub struct Counter {
-            pub total: usize,
-            pub leaves: usize,
-        }
+            pub fn push_child(&self, node: Node) -> SharedNode {
+                let child = SharedNode::from(node);
+                self.0.write().children.push(child.clone());
+                child
+            }
 
-        // two independent types need different impls of dfs
-        fn dfs_names(node: &Node, state: &mut Index) {
-            state.names.insert(node.name.clone());
+            // the removed subtree lives on for as long as there are handles
+            // to it, it just isn't in the tree any more
+            pub fn remove_child(&self, index: usize) -> Option<SharedNode> {
+                let mut inner = self.0.write();
+                (index < inner.children.len()).then(|| inner.children.remove(index))
+            }
 
-            for child in &node.children {
-                dfs_names(child, state);
+            // swaps in a whole new subtree at once. it is built before the
+            // lock is taken, so readers only ever see the old one or the new
+            pub fn replace(&self, node: Node) {
+                let new = SharedNode::from(node);
+                let mut new = new.0.write();
+                let mut inner = self.0.write();
+                mem::swap(&mut inner.name, &mut new.name);
+                mem::swap(&mut inner.size, &mut new.size);
+                mem::swap(&mut inner.children, &mut new.children);
             }
-        }
 
-        fn dfs_count(node: &Node, state: &mut Counter) {
-            if node.children.is_empty() {
-                state.leaves += 1;
+            // a copy of the subtree as it was at one moment. every node is
+            // read locked on the way down and stays locked until the copy is
+            // done, so an edit is either all there or not there at all, and
+            // edits made one after the other show up in that order.
+            // snapshots lock in pre-order and nodes never move, so any two
+            // take their locks in the same order, and writers hold only one:
+            // nothing can deadlock, even with readers queueing behind writers
+            pub fn snapshot(&self) -> Node {
+                let mut guards = vec![];
+                let mut stack = vec![self.0.clone()];
+                while let Some(lock) = stack.pop() {
+                    let guard = lock.read_arc();
+                    stack.extend(guard.children.iter().rev().map(|c| c.0.clone()));
+                    guards.push(guard);
+                }
+
+                // pre-order, so going backwards every child is done before
+                // its parent needs it
+                let mut done: Vec<Node> = vec![];
+                for guard in guards.iter().rev() {
+                    let at = done.len() - guard.children.len();
+                    let mut children = done.split_off(at);
+                    children.reverse();
+                    let mut node = Node::new(&guard.name, children);
+                    node.size = guard.size;
+                    done.push(node);
+                }
+                done.pop().expect("the root is always copied")
             }
-            state.total += 1;
 
-            for child in &node.children {
-                dfs_count(child, state);
+            // same node, not just equal
+            pub fn ptr_eq(&self, other: &SharedNode) -> bool {
+                Arc::ptr_eq(&self.0, &other.0)
             }
         }
 
-        pub trait Visitor {
-            fn process(&mut self, node: &Node);
+        impl From<Node> for SharedNode {
+            fn from(mut root: Node) -> Self {
+                // children first, then the parent that takes them
+                let mut done: Vec<SharedNode> = vec![];
+                let mut stack = vec![(
+                    mem::take(&mut root.name),
+                    root.size,
+                    mem::take(&mut root.children),
+                    false,
+                )];
+
+                while let Some((name, size, mut children, expanded)) = stack.pop() {
+                    if expanded {
+                        let at = done.len() - children.len();
+                        let kids = done.split_off(at);
+                        done.push(SharedNode::from_parts(name, size, kids));
+                        continue;
+                    }
+                    let taken: Vec<_> = children
+                        .iter_mut()
+                        .map(|c| {
+                            (
+                                mem::take(&mut c.name),
+                                c.size,
+                                mem::take(&mut c.children),
+                                false,
+                            )
+                        })
+                        .collect();
+                    stack.push((name, size, children, true));
+                    stack.extend(taken.into_iter().rev());
+                }
+                done.pop().expect("the root is always built")
+            }
         }
 
-        // two independent types need different impls of dfs
-        // make it generic over _visitor_ type
-        fn dfs<V>(node: &Node, visitor: &mut V)
-        where
-            V: Visitor,
-        {
-            visitor.process(node);
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+            use crate::stateful::tests::sample;
+            use std::{
+                sync::{
+                    atomic::{AtomicUsize, Ordering},
+                    Barrier,
+                },
+                thread,
+            };
 
-            for child in &node.children {
-                dfs(child, visitor);
+            #[test]
+            fn edits_and_snapshots() {
+                let root = SharedNode::from(sample());
+                assert_eq!(root.snapshot(), sample());
+
+                let b = root.child(0).unwrap();
+                b.rename("B");
+                b.child(1).unwrap().set_size(Some(7));
+                let f = root.push_child(Node::new("f", vec![]));
+                f.insert_child(0, Node::new("g", vec![]));
+                let e = root.remove_child(1).unwrap();
+                assert!(root.remove_child(5).is_none());
+
+                let mut expected = sample();
+                expected.children[0].name = "B".into();
+                expected.children[0].children[1].size = Some(7);
+                expected.children[1] = Node::new("f", vec![Node::new("g", vec![])]);
+                assert_eq!(root.snapshot(), expected);
+
+                // a removed subtree still works, on its own
+                e.push_child(Node::new("x", vec![]));
+                assert_eq!(e.snapshot(), Node::new("e", vec![Node::new("x", vec![])]));
+
+                // a handle follows its node through a replace
+                b.replace(Node::new("new", vec![Node::new("h", vec![])]));
+                assert!(root.child(0).unwrap().ptr_eq(&b));
+                assert_eq!(
+                    root.snapshot().children[0],
+                    Node::new("new", vec![Node::new("h", vec![])])
+                );
+                assert_eq!(b.children().len(), 1);
             }
-        }
 
-        impl Visitor for Index {
-            fn process(&mut self, node: &Node) {
-                self.names.insert(node.name.clone());
+            #[test]
+            fn deep_chain() {
+                let root = SharedNode::from(crate::stateful::tests::chain(1_000_000));
+                let copy = root.snapshot();
+                assert_eq!(copy.iter_pre().count(), 1_000_000);
             }
-        }
 
-        impl Visitor for Counter {
-            fn process(&mut self, node: &Node) {
-                if node.children.is_empty() {
-                    self.leaves += 1;
+            const WRITERS: usize = 4;
+            const READERS: usize = 8;
+            const ROUNDS: u64 = 10_000;
+
+            // the `w{i}` subtree: writer i's two counters and a scratch
+            // subtree it churns
+            fn lane(i: usize) -> Node {
+                Node::new(
+                    &format!("w{}", i),
+                    vec![
+                        Node::new("first", vec![]),
+                        Node::new("second", vec![]),
+                        Node::new("scratch", vec![]),
+                    ],
+                )
+            }
+
+            // `n` children named after their position, sizes giving how many
+            // there are. correct only if a whole replace is seen at once
+            fn batch(n: u64) -> Node {
+                let children = (0..n).map(|i| Node::new(&i.to_string(), vec![])).collect();
+                let mut node = Node::new("batch", children);
+                node.size = Some(n);
+                node
+            }
+
+            fn check(snapshot: &Node) {
+                assert_eq!(snapshot.children.len(), WRITERS);
+                for lane in &snapshot.children {
+                    let [first, second, scratch] = &lane.children[..] else {
+                        panic!("{} lost a child", lane.name)
+                    };
+                    // `first` is always bumped before `second`, so any real
+                    // state of the tree has second <= first, even though a
+                    // walk reads `first` before `second`
+                    let count = |n: &Node| n.size.unwrap_or(0);
+                    assert!(
+                        count(second) <= count(first),
+                        "{:?} > {:?}",
+                        second.size,
+                        first.size
+                    );
+                    assert!(count(first) <= count(second) + 1);
+
+                    for batch in &scratch.children {
+                        assert_eq!(batch.size, Some(batch.children.len() as u64));
+                        let names = batch
+                            .children
+                            .iter()
+                            .map(|c| c.name.parse::<usize>().unwrap());
+                        assert!(names.eq(0..batch.children.len()));
+                    }
+                }
+            }
+
+            #[test]
+            fn readers_and_writers() {
+                let root = SharedNode::from(Node::new("root", (0..WRITERS).map(lane).collect()));
+                let start = Barrier::new(WRITERS + READERS);
+                let writing = AtomicUsize::new(WRITERS);
+
+                thread::scope(|scope| {
+                    for i in 0..WRITERS {
+                        let (root, start, writing) = (&root, &start, &writing);
+                        scope.spawn(move || {
+                            let lane = root.child(i).unwrap();
+                            let [first, second, scratch] = &lane.children()[..] else {
+                                unreachable!()
+                            };
+                            start.wait();
+                            for round in 1..=ROUNDS {
+                                first.set_size(Some(round));
+                                second.set_size(Some(round));
+
+                                match round % 3 {
+                                    0 => {
+                                        scratch.push_child(batch(round % 5));
+                                    }
+                                    1 => {
+                                        scratch.remove_child(0);
+                                    }
+                                    _ => match scratch.child(0) {
+                                        Some(b) => b.replace(batch(round % 7)),
+                                        None => {
+                                            scratch.insert_child(0, batch(1));
+                                        }
+                                    },
+                                }
+                            }
+                            writing.fetch_sub(1, Ordering::SeqCst);
+                        });
+                    }
+
+                    for _ in 0..READERS {
+                        let (root, start, writing) = (&root, &start, &writing);
+                        scope.spawn(move || {
+                            start.wait();
+                            let mut seen = 0;
+                            while writing.load(Ordering::SeqCst) > 0 || seen == 0 {
+                                check(&root.snapshot());
+                                seen += 1;
+                            }
+                        });
+                    }
+                });
+
+                let last = root.snapshot();
+                check(&last);
+                for lane in &last.children {
+                    assert_eq!(lane.children[0].size, Some(ROUNDS));
+                    assert_eq!(lane.children[1].size, Some(ROUNDS));
                 }
-                self.total += 1;
             }
         }
     }
 
-    mod nodetype {
-        // make visitor generic over node type N
-        pub trait Visitor<N> {
-            fn process(&mut self, node: &N);
-        }
+    // a local stand-in for the kind of service async visitors talk to, and
+    // one such visitor. the service takes a name per line and answers with
+    // it upper-cased, after a short wait
+    #[cfg(test)]
+    mod stand_in {
+        use super::{
+            tests::wide,
+            tree::{walk_async, walk_pre, AsyncVisitor, Control},
+            Node,
+        };
+        use std::{
+            net::SocketAddr,
+            num::NonZeroUsize,
+            sync::{
+                atomic::{AtomicUsize, Ordering},
+                Arc, Mutex,
+            },
+            time::Duration,
+        };
+        use tokio::{
+            io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+            net::{TcpListener, TcpStream},
+            time::sleep,
+        };
 
-        trait TreeLike: Sized {
-            fn children(&self) -> &[Self];
+        #[derive(Default)]
+        struct Load {
+            now: AtomicUsize,
+            most: AtomicUsize,
+            served: AtomicUsize,
         }
 
-        fn dfs<N, V>(node: &N, visitor: &mut V)
-        where
-            N: TreeLike,
-            V: Visitor<N>,
-        {
-            visitor.process(node);
+        // listens on a free local port until the runtime goes away
+        async fn serve(delay: Duration) -> (SocketAddr, Arc<Load>) {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let load = Arc::new(Load::default());
+
+            let stats = load.clone();
+            tokio::spawn(async move {
+                loop {
+                    let (socket, _) = listener.accept().await.unwrap();
+                    let load = stats.clone();
+                    tokio::spawn(async move {
+                        let (read, mut write) = socket.into_split();
+                        let mut lines = BufReader::new(read).lines();
+                        while let Some(name) = lines.next_line().await.unwrap() {
+                            let now = load.now.fetch_add(1, Ordering::SeqCst) + 1;
+                            load.most.fetch_max(now, Ordering::SeqCst);
+                            sleep(delay).await;
+                            load.now.fetch_sub(1, Ordering::SeqCst);
+                            load.served.fetch_add(1, Ordering::SeqCst);
+
+                            let reply = format!("{}\n", name.to_uppercase());
+                            write.write_all(reply.as_bytes()).await.unwrap();
+                        }
+                    });
+                }
+            });
 
-            for child in node.children() {
-                dfs(child, visitor);
-            }
+            (addr, load)
         }
 
-        #[derive(Debug)]
-        pub struct CoolNode {
-            pub name: String,
-            pub nick: String,
-            pub children: Vec<CoolNode>,
+        // looks every name up, one connection per request
+        struct Lookup {
+            addr: SocketAddr,
+            answers: Mutex<Vec<String>>,
         }
 
-        impl TreeLike for CoolNode {
-            fn children(&self) -> &[Self] {
-                &self.children
+        impl AsyncVisitor<Node> for Lookup {
+            async fn process(&self, node: &Node) -> Control {
+                let socket = TcpStream::connect(self.addr).await.unwrap();
+                let (read, mut write) = socket.into_split();
+                write
+                    .write_all(format!("{}\n", node.name).as_bytes())
+                    .await
+                    .unwrap();
+
+                let mut answer = String::new();
+                BufReader::new(read).read_line(&mut answer).await.unwrap();
+                self.answers
+                    .lock()
+                    .unwrap()
+                    .push(answer.trim_end().to_string());
+                Control::Continue
             }
         }
-    }
 
-    mod typestate {
-        // make visitor generic over node type N
-        pub trait Visitor<N> {
-            fn process(&mut self, node: &N);
+        // tokio::test would do, but its expansion allows a lint this crate
+        // forbids
+        fn block_on<F: Future>(test: F) -> F::Output {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(test)
         }
 
-        trait TreeLike: Sized {
-            fn children(&self) -> &[Self];
+        #[test]
+        fn bounded_concurrency() {
+            block_on(async {
+                let (addr, load) = serve(Duration::from_millis(5)).await;
+                let lookup = Lookup {
+                    addr,
+                    answers: Mutex::new(vec![]),
+                };
+
+                // 1 + 4 + 16 + 64 nodes
+                let root = wide(4, 3);
+                let limit = NonZeroUsize::new(8).unwrap();
+                assert_eq!(walk_async(&root, &lookup, limit).await, Control::Continue);
+
+                let answers = lookup.answers.into_inner().unwrap();
+                assert_eq!(answers.len(), 85);
+                let leaves = answers.iter().filter(|a| a.matches('.').count() == 3);
+                assert_eq!(leaves.count(), 64);
+                assert_eq!(load.served.load(Ordering::SeqCst), 85);
+
+                let most = load.most.load(Ordering::SeqCst);
+                assert!(most > 1 && most <= 8, "{} requests at once", most);
+            })
         }
 
-        fn dfs<N, V>(node: &N, visitor: &mut V)
-        where
-            N: TreeLike,
-            V: Visitor<N>,
-        {
-            visitor.process(node);
+        #[test]
+        fn limit_of_one_is_pre_order() {
+            block_on(async {
+                let (addr, load) = serve(Duration::ZERO).await;
+                let lookup = Lookup {
+                    addr,
+                    answers: Mutex::new(vec![]),
+                };
+                let root = Node::new(
+                    "a",
+                    vec![
+                        Node::new("b", vec![Node::new("c", vec![])]),
+                        Node::new("d", vec![]),
+                    ],
+                );
+
+                walk_async(&root, &lookup, NonZeroUsize::MIN).await;
+                assert_eq!(*lookup.answers.lock().unwrap(), ["A", "B", "C", "D"]);
+                assert_eq!(load.most.load(Ordering::SeqCst), 1);
+
+                let mut names = vec![];
+                walk_pre(&root, &mut |n: &Node| names.push(n.name.to_uppercase()));
+                assert_eq!(names, *lookup.answers.lock().unwrap());
+            })
+        }
 
-            for child in node.children() {
-                dfs(child, visitor);
-            }
+        #[test]
+        fn skip_and_stop() {
+            block_on(async {
+                let root = wide(4, 3);
+                let depth = |n: &Node| n.name.matches('.').count();
+                let seen = Mutex::new(vec![]);
+
+                // parents always finish before their children start
+                let skip = async |n: &Node| {
+                    sleep(Duration::from_millis(1)).await;
+                    seen.lock().unwrap().push(n.name.clone());
+                    if depth(n) == 2 {
+                        Control::SkipChildren
+                    } else {
+                        Control::Continue
+                    }
+                };
+                let limit = NonZeroUsize::new(4).unwrap();
+                assert_eq!(walk_async(&root, &skip, limit).await, Control::Continue);
+                let names = std::mem::take(&mut *seen.lock().unwrap());
+                assert_eq!(names.len(), 1 + 4 + 16);
+                assert_eq!(names[0], "r");
+                assert!(names.iter().all(|n| n.matches('.').count() < 3));
+
+                // one at a time, so nothing else is in flight when it stops
+                let visited = AtomicUsize::new(0);
+                let stop = async |_: &Node| {
+                    if visited.fetch_add(1, Ordering::SeqCst) == 10 {
+                        Control::Stop
+                    } else {
+                        Control::Continue
+                    }
+                };
+                let result = walk_async(&root, &stop, NonZeroUsize::MIN).await;
+                assert_eq!(result, Control::Stop);
+                assert_eq!(visited.load(Ordering::SeqCst), 11);
+            })
         }
+    }
 
-        // make node generic
-        #[derive(Debug)]
-        pub struct Node<T> {
-            pub name: String,
-            pub children: Vec<Node<T>>,
-            _marker: std::marker::PhantomData<T>,
+    #[cfg(test)]
+    mod tests {
+        use super::tree::{
+            walk_enter_leave, walk_level_order, walk_post, walk_pre, walk_with_context,
+        };
+        use super::*;
+
+        //       a
+        //     /   \
+        //    b     e
+        //   / \
+        //  c   d
+        pub(super) fn sample() -> Node {
+            Node::new(
+                "a",
+                vec![
+                    Node::new("b", vec![Node::new("c", vec![]), Node::new("d", vec![])]),
+                    Node::new("e", vec![]),
+                ],
+            )
         }
 
-        impl<T> TreeLike for Node<T> {
-            fn children(&self) -> &[Self] {
-                &self.children
+        #[derive(Default)]
+        struct Names(Vec<String>);
+
+        impl Visitor<Node> for Names {
+            fn process(&mut self, node: &Node) -> Control {
+                self.0.push(node.name.clone());
+                Control::Continue
             }
         }
 
-        struct Graph;
-        struct Tree;
+        fn names(walk: fn(&Node, &mut Names) -> Control) -> Vec<String> {
+            let mut names = Names::default();
+            walk(&sample(), &mut names);
+            names.0
+        }
 
-        type GraphNode = Node<Graph>;
-        type TreeNode = Node<Tree>;
+        #[test]
+        fn pre_post_and_level_order() {
+            assert_eq!(names(walk_pre), ["a", "b", "c", "d", "e"]);
+            assert_eq!(names(walk_post), ["c", "d", "b", "e", "a"]);
+            assert_eq!(names(walk_level_order), ["a", "b", "e", "c", "d"]);
+        }
 
-        impl GraphNode {
-            // only makes sense on Node in a Graph
-            pub fn has_cycle(&self) -> bool {
-                false
-            }
+        #[test]
+        fn enter_leave_subtree_sizes() {
+            let mut sizes = Sizes::default();
+            walk_enter_leave(&sample(), &mut sizes);
+
+            let sizes: Vec<(&str, usize)> =
+                sizes.sizes.iter().map(|(n, s)| (n.as_str(), *s)).collect();
+            assert_eq!(sizes, [("c", 1), ("d", 1), ("b", 3), ("e", 1), ("a", 5)]);
         }
 
-        impl TreeNode {
-            // only makes sense on Node in a Tree
-            pub fn balanced(&self) -> bool {
-                false
-            }
+        #[test]
+        fn find_stops_early() {
+            let mut find = Find {
+                name: "c",
+                seen: 0,
+                found: false,
+            };
+
+            assert_eq!(walk_pre(&sample(), &mut find), Control::Stop);
+            assert!(find.found);
+            assert_eq!(find.seen, 3);
         }
-    }
 
-    mod registry {
-        use super::{
-            visitor::{Counter, Index, Visitor},
-            Node,
-        };
+        // prunes below "b", recording what it's shown
+        #[derive(Default)]
+        pub(super) struct Prune(pub(super) Vec<String>);
+
+        impl Visitor<Node> for Prune {
+            fn process(&mut self, node: &Node) -> Control {
+                self.0.push(node.name.clone());
+                if node.name == "b" {
+                    Control::SkipChildren
+                } else {
+                    Control::Continue
+                }
+            }
 
-        fn dfs_without_registry(node: &Node, visitors: &mut Vec<impl Visitor>) {
-            for visitor in visitors.iter_mut() {
-                visitor.process(node);
+            fn enter(&mut self, node: &Node) -> Control {
+                self.process(node)
             }
 
-            for child in &node.children {
-                dfs_without_registry(child, visitors);
+            fn leave(&mut self, node: &Node) {
+                self.0.push(format!("/{}", node.name));
             }
         }
 
-        struct Registry {
-            node: Node,
-            visitors: Vec<Box<dyn Visitor>>,
+        #[test]
+        fn skip_children() {
+            let mut prune = Prune(vec![]);
+            assert_eq!(walk_pre(&sample(), &mut prune), Control::Continue);
+            assert_eq!(prune.0, ["a", "b", "e"]);
+
+            let mut prune = Prune(vec![]);
+            walk_level_order(&sample(), &mut prune);
+            assert_eq!(prune.0, ["a", "b", "e"]);
+
+            let mut prune = Prune(vec![]);
+            walk_enter_leave(&sample(), &mut prune);
+            assert_eq!(prune.0, ["a", "b", "/b", "e", "/e", "/a"]);
         }
 
-        #[derive(Default)]
-        struct RegistryBuilder {
-            visitors: Vec<Box<dyn Visitor>>,
+        fn collect<'a>(nodes: impl Iterator<Item = &'a Node>) -> Vec<&'a str> {
+            nodes.map(|n| n.name.as_str()).collect()
         }
 
-        impl RegistryBuilder {
-            fn new() -> Self {
-                RegistryBuilder::default()
-            }
+        #[test]
+        fn iterators_match_walkers() {
+            let root = sample();
 
-            fn visitor(mut self, visitor: Box<dyn Visitor>) -> Self {
-                self.visitors.push(visitor);
-                self
-            }
+            assert_eq!(collect(root.iter_pre()), names(walk_pre));
+            assert_eq!(collect(root.iter_post()), names(walk_post));
+            assert_eq!(collect(root.iter_bfs()), names(walk_level_order));
 
-            // interface cleanup: without boxed value being passed in
-            fn add_visitor<V>(mut self, visitor: V) -> Self
-            where
-                V: Visitor + 'static,
-            {
-                self.visitors.push(Box::new(visitor));
-                self
-            }
+            let depths: Vec<(usize, &str)> = root
+                .iter_pre_with_depth()
+                .map(|(d, n)| (d, n.name.as_str()))
+                .collect();
+            assert_eq!(depths, [(0, "a"), (1, "b"), (2, "c"), (2, "d"), (1, "e")]);
+        }
 
-            fn build(self, node: Node) -> Registry {
-                Registry {
-                    node,
-                    visitors: self.visitors,
+        #[test]
+        fn iterators_with_adaptors() {
+            use itertools::Itertools;
+
+            let root = sample();
+
+            let leaves = collect(root.iter_pre().filter(|n| n.children.is_empty()));
+            assert_eq!(leaves, ["c", "d", "e"]);
+
+            let until_e = collect(root.iter_bfs().take_while(|n| n.name != "e"));
+            assert_eq!(until_e, ["a", "b"]);
+
+            let levels: Vec<Vec<&str>> = root
+                .iter_bfs_with_depth()
+                .group_by(|(depth, _)| *depth)
+                .into_iter()
+                .map(|(_, level)| level.map(|(_, n)| n.name.as_str()).collect())
+                .collect();
+            assert_eq!(levels, [vec!["a"], vec!["b", "e"], vec!["c", "d"]]);
+        }
+
+        #[test]
+        fn context_visitors() {
+            let mut leaves = LeafPaths::default();
+            walk_with_context(&sample(), &mut leaves);
+            assert_eq!(leaves.paths, ["a/b/c", "a/b/d", "a/e"]);
+
+            let root = Node::new(
+                "src",
+                vec![
+                    Node::new(
+                        "src",
+                        vec![Node::new("lib", vec![Node::new("lib", vec![])])],
+                    ),
+                    Node::new("bin", vec![]),
+                ],
+            );
+            let mut stutters = Stutters::default();
+            walk_with_context(&root, &mut stutters);
+            assert_eq!(stutters.found, ["src/src", "src/src/lib/lib"]);
+        }
+
+        #[test]
+        fn context_fields() {
+            let mut seen = vec![];
+            walk_with_context(&sample(), &mut |n: &Node, cx: &tree::Context<Node>| {
+                let parent = cx.parent().map(|p| p.name.clone());
+                seen.push((n.name.clone(), cx.depth, cx.index, parent));
+            });
+
+            let expected = [
+                ("a", 0, None, None),
+                ("b", 1, Some(0), Some("a")),
+                ("c", 2, Some(0), Some("b")),
+                ("d", 2, Some(1), Some("b")),
+                ("e", 1, Some(1), Some("a")),
+            ]
+            .map(|(n, d, i, p)| (n.to_string(), d, i, p.map(String::from)));
+            assert_eq!(seen, expected);
+
+            // skipping b's children must not leave b on the ancestor stack
+            struct SkipB(Vec<String>);
+            impl tree::ContextVisitor<Node> for SkipB {
+                fn process(&mut self, n: &Node, cx: &tree::Context<Node>) -> Control {
+                    self.0.push(cx.path(n, |n| &n.name));
+                    if n.name == "b" {
+                        Control::SkipChildren
+                    } else {
+                        Control::Continue
+                    }
                 }
             }
+            let mut skip = SkipB(vec![]);
+            walk_with_context(&sample(), &mut skip);
+            assert_eq!(skip.0, ["a", "a/b", "a/e"]);
+        }
+
+        #[test]
+        fn fallible_visitors() {
+            use super::tree::{try_walk, try_walk_all, WalkError};
+
+            assert_eq!(try_walk(&sample(), &mut Validate), Ok(Control::Continue));
+
+            // a
+            // ├── b
+            // │   ├── ""
+            // │   └── d
+            // ├── e
+            // │   ├── x
+            // │   └── x
+            // └── ""
+            let root = Node::new(
+                "a",
+                vec![
+                    Node::new("b", vec![Node::new("", vec![]), Node::new("d", vec![])]),
+                    Node::new("e", vec![Node::new("x", vec![]), Node::new("x", vec![])]),
+                    Node::new("", vec![]),
+                ],
+            );
+
+            let first = try_walk(&root, &mut Validate).unwrap_err();
+            assert_eq!(
+                first,
+                WalkError {
+                    path: vec![0, 0],
+                    error: Invalid::EmptyName,
+                }
+            );
+            assert_eq!(first.to_string(), "at [0, 0]: empty name");
+
+            let all = try_walk_all(&root, &mut Validate).unwrap_err();
+            let found: Vec<_> = all.iter().map(|e| (e.path.as_slice(), &e.error)).collect();
+            assert_eq!(
+                found,
+                [
+                    (&[0, 0][..], &Invalid::EmptyName),
+                    (&[1][..], &Invalid::DuplicateChild("x".into())),
+                    (&[2][..], &Invalid::EmptyName),
+                ]
+            );
+
+            // closures work too: `?` inside them ends the walk at that node
+            let mut visited = 0;
+            let err = try_walk(&root, &mut |n: &Node| {
+                visited += 1;
+                n.name.parse::<char>()?;
+                Ok::<_, std::char::ParseCharError>(Control::Continue)
+            })
+            .unwrap_err();
+            assert_eq!(visited, 3);
+            assert_eq!(err.path, [0, 0]);
+            assert_eq!(err.error, "".parse::<char>().unwrap_err());
+
+            // and a plain Stop is still an Ok
+            let mut visited = 0;
+            let result = try_walk(&root, &mut |n: &Node| {
+                visited += 1;
+                Ok::<_, Invalid>(if n.name.is_empty() {
+                    Control::Stop
+                } else {
+                    Control::Continue
+                })
+            });
+            assert_eq!((result, visited), (Ok(Control::Stop), 3));
+        }
+
+        // a file under fixtures/
+        pub(super) fn fixture(name: &str) -> String {
+            format!("{}/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
         }
 
-        fn dfs_with_registry(node: &Node, registry: &mut Registry) {
-            for visitor in registry.visitors.iter_mut() {
-                visitor.process(node);
+        pub(super) fn leaf(name: &str) -> Node {
+            Node::new(name, vec![])
+        }
+
+        // `fanout` children per node, `depth` levels below the root. names
+        // spell the path: r, r.0, r.0.1, ...
+        pub(super) fn wide(fanout: usize, depth: usize) -> Node {
+            fn grow(name: String, fanout: usize, depth: usize) -> Node {
+                let children = if depth == 0 {
+                    vec![]
+                } else {
+                    (0..fanout)
+                        .map(|i| grow(format!("{}.{}", name, i), fanout, depth - 1))
+                        .collect()
+                };
+                Node::new(&name, children)
             }
+            grow("r".into(), fanout, depth)
+        }
 
-            for child in &node.children {
-                dfs_with_registry(child, registry);
+        // root -> link -> link -> ... -> leaf, built bottom up
+        pub(super) fn chain(depth: usize) -> Node {
+            let mut node = Node::new("leaf", vec![]);
+            for _ in 1..depth {
+                node = Node::new("link", vec![node]);
             }
+            node
         }
 
-        #[derive(Debug)]
-        struct Printer;
+        #[derive(Default)]
+        struct Count(usize);
 
-        impl Visitor for Printer {
-            fn process(&mut self, node: &Node) {
-                println!("{:#?}", node);
+        impl Visitor<Node> for Count {
+            fn process(&mut self, node: &Node) -> Control {
+                self.0 += 1;
+                Control::Continue
             }
         }
 
-        fn visit_all(node: Node) {
-            let mut rb = RegistryBuilder::new()
-                .visitor(Box::new(Printer))
-                .visitor(Box::new(Counter::default()))
-                .visitor(Box::new(Index::default()))
-                .build(node.clone());
+        #[test]
+        fn million_deep_chain() {
+            const DEPTH: usize = 1_000_000;
+            let root = chain(DEPTH);
 
-            dfs_with_registry(&node, &mut rb);
+            for walk in [walk_pre, walk_post, walk_level_order] {
+                let mut count = Count::default();
+                walk(&root, &mut count);
+                assert_eq!(count.0, DEPTH);
+            }
 
-            let mut rb = RegistryBuilder::new()
-                .add_visitor(Printer)
-                .add_visitor(Counter::default())
-                .add_visitor(Index::default())
-                .build(node.clone());
+            let mut sizes = Sizes::default();
+            walk_enter_leave(&root, &mut sizes);
+            assert_eq!(sizes.sizes.last(), Some(&("link".to_string(), DEPTH)));
 
-            dfs_with_registry(&node, &mut rb);
+            assert_eq!(
+                tree::try_walk_all(&root, &mut Validate),
+                Ok(Control::Continue)
+            );
+
+            let mut deepest = 0;
+            walk_with_context(&root, &mut |_: &Node, cx: &tree::Context<Node>| {
+                deepest = deepest.max(cx.ancestors.len())
+            });
+            assert_eq!(deepest, DEPTH - 1);
+
+            let mut names = HashSet::new();
+            var::dfs(&root, &mut names);
+            assert_eq!(names.len(), 2);
+
+            let mut total = 0;
+            hof::dfs(&root, &mut |_: &Node| total += 1);
+            assert_eq!(total, DEPTH);
+
+            let mut state = typed::State::default();
+            typed::dfs(&root, &mut state);
+            assert_eq!(state.total, DEPTH);
+
+            assert_eq!(root.iter_pre().count(), DEPTH);
+            assert_eq!(root.iter_post().count(), DEPTH);
+            assert_eq!(root.iter_bfs().count(), DEPTH);
+
+            // and dropping it must not overflow either
+            drop(root);
         }
     }
 }
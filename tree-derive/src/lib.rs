@@ -0,0 +1,153 @@
+// #[derive(TreeLike)] for the tree types in `practice`. the generated impl
+// names `TreeLike` unqualified, so the trait has to be in scope wherever the
+// derive is used:
+//
+//   use crate::stateful::tree::TreeLike;
+//
+//   #[derive(TreeLike)]
+//   struct Node {
+//       name: String,
+//       #[tree(children)]
+//       children: Vec<Node>,
+//   }
+//
+// the children field can be a Vec<Self>, an Option<Box<Self>> (at most one
+// child, e.g. a linked list) or a Box<[Self]>
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{ToTokens, quote};
+use syn::{Data, DeriveInput, Error, Field, Ident, Index, PathArguments, Type, parse_macro_input};
+
+#[proc_macro_derive(TreeLike, attributes(tree))]
+pub fn derive_tree_like(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+// the shapes of children field we know how to view as a slice
+enum Container {
+    Vec,
+    OptionBox,
+    BoxSlice,
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let Data::Struct(data) = &input.data else {
+        return Err(Error::new_spanned(
+            &input.ident,
+            "TreeLike can only be derived for structs",
+        ));
+    };
+
+    let mut marked: Vec<(usize, &Field)> = vec![];
+    for (i, field) in data.fields.iter().enumerate() {
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("tree")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("children") {
+                    Ok(())
+                } else {
+                    Err(meta.error("expected `children`"))
+                }
+            })?;
+            marked.push((i, field));
+        }
+    }
+
+    let (index, field) = match marked[..] {
+        [one] => one,
+        [] => {
+            return Err(Error::new(
+                Span::call_site(),
+                "mark the field holding the children with #[tree(children)]",
+            ));
+        }
+        [_, (_, second), ..] => {
+            return Err(Error::new_spanned(
+                second,
+                "only one field can be #[tree(children)]",
+            ));
+        }
+    };
+
+    let member = match &field.ident {
+        Some(ident) => ident.to_token_stream(),
+        None => Index::from(index).to_token_stream(),
+    };
+    let body = match container(&field.ty, &input.ident)? {
+        Container::Vec | Container::BoxSlice => quote!(&self.#member),
+        Container::OptionBox => quote! {
+            match &self.#member {
+                ::core::option::Option::Some(child) => ::core::slice::from_ref(&**child),
+                ::core::option::Option::None => &[],
+            }
+        },
+    };
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics TreeLike for #name #ty_generics #where_clause {
+            fn children(&self) -> &[Self] {
+                #body
+            }
+        }
+    })
+}
+
+fn container(ty: &Type, name: &Ident) -> syn::Result<Container> {
+    let found = match outer(ty) {
+        Some(("Vec", Some(inner))) if is_self(inner, name) => Some(Container::Vec),
+        Some(("Option", Some(inner))) => match outer(inner) {
+            Some(("Box", Some(inner))) if is_self(inner, name) => Some(Container::OptionBox),
+            _ => None,
+        },
+        Some(("Box", Some(Type::Slice(slice)))) if is_self(&slice.elem, name) => {
+            Some(Container::BoxSlice)
+        }
+        _ => None,
+    };
+
+    found.ok_or_else(|| {
+        Error::new_spanned(
+            ty,
+            "#[tree(children)] must be a Vec<Self>, Option<Box<Self>> or Box<[Self]>",
+        )
+    })
+}
+
+// `Foo<T>` as ("Foo", Some(T)), for a path type with at most one type argument
+fn outer(ty: &Type) -> Option<(&str, Option<&Type>)> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let last = path.path.segments.last()?;
+    let arg = match &last.arguments {
+        PathArguments::AngleBracketed(args) => match args.args.first() {
+            Some(syn::GenericArgument::Type(ty)) if args.args.len() == 1 => Some(ty),
+            _ => return None,
+        },
+        PathArguments::None => None,
+        PathArguments::Parenthesized(_) => return None,
+    };
+
+    // only the containers `container` knows about
+    ["Vec", "Option", "Box"]
+        .into_iter()
+        .find(|n| last.ident == n)
+        .map(|n| (n, arg))
+}
+
+// `Self`, or the type being derived for spelled out (generic arguments
+// aren't checked; the compiler will catch a mismatch there)
+fn is_self(ty: &Type, name: &Ident) -> bool {
+    match ty {
+        Type::Path(path) => {
+            path.qself.is_none()
+                && (path.path.is_ident("Self")
+                    || (path.path.segments.len() == 1 && path.path.segments[0].ident == *name))
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,8 @@
+// run with TRYBUILD=overwrite to regenerate the .stderr files after a
+// deliberate change to an error message
+#[test]
+fn ui() {
+    let t = trybuild::TestCases::new();
+    t.pass("tests/ui/containers.rs");
+    t.compile_fail("tests/ui/fail_*.rs");
+}
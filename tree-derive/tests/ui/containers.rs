@@ -0,0 +1,43 @@
+use tree_derive::TreeLike;
+
+trait TreeLike: Sized {
+    fn children(&self) -> &[Self];
+}
+
+#[derive(TreeLike)]
+struct Node {
+    name: String,
+    #[tree(children)]
+    children: Vec<Node>,
+}
+
+#[derive(TreeLike)]
+struct Link(u32, #[tree(children)] Option<Box<Self>>);
+
+#[derive(TreeLike)]
+struct Frozen<T> {
+    value: T,
+    #[tree(children)]
+    children: Box<[Frozen<T>]>,
+}
+
+fn main() {
+    let node = Node {
+        name: "a".into(),
+        children: vec![Node {
+            name: "b".into(),
+            children: vec![],
+        }],
+    };
+    assert_eq!(node.children()[0].name, "b");
+
+    let list = Link(1, Some(Box::new(Link(2, None))));
+    assert_eq!(list.children()[0].0, 2);
+    assert!(list.children()[0].children().is_empty());
+
+    let frozen = Frozen {
+        value: 'a',
+        children: Box::new([]),
+    };
+    assert!(frozen.children().is_empty() && frozen.value == 'a');
+}
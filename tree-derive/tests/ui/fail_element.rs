@@ -0,0 +1,14 @@
+use tree_derive::TreeLike;
+
+trait TreeLike: Sized {
+    fn children(&self) -> &[Self];
+}
+
+#[derive(TreeLike)]
+struct Node {
+    name: String,
+    #[tree(children)]
+    children: Vec<String>,
+}
+
+fn main() {}
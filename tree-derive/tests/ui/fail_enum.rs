@@ -0,0 +1,13 @@
+use tree_derive::TreeLike;
+
+trait TreeLike: Sized {
+    fn children(&self) -> &[Self];
+}
+
+#[derive(TreeLike)]
+enum Node {
+    Leaf,
+    Branch(Vec<Node>),
+}
+
+fn main() {}
@@ -0,0 +1,13 @@
+use tree_derive::TreeLike;
+
+trait TreeLike: Sized {
+    fn children(&self) -> &[Self];
+}
+
+#[derive(TreeLike)]
+struct Node {
+    name: String,
+    children: Vec<Node>,
+}
+
+fn main() {}
@@ -0,0 +1,16 @@
+use tree_derive::TreeLike;
+
+trait TreeLike: Sized {
+    fn children(&self) -> &[Self];
+}
+
+#[derive(TreeLike)]
+struct Node {
+    name: String,
+    #[tree(children)]
+    children: Vec<Node>,
+    #[tree(children)]
+    more: Vec<Node>,
+}
+
+fn main() {}